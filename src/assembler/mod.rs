@@ -0,0 +1,414 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+};
+
+use crate::{
+    diagnostics::Diagnostic,
+    parser::{
+        expression::{evaluate, is_expression},
+        instruction::{Instruction, Opcode},
+        Item,
+    },
+};
+
+/// The number of mailboxes available to an LMC program
+pub const MAILBOXES: usize = 100;
+
+/// A program assembled into machine code, ready to be loaded into the LMC's mailboxes
+#[derive(PartialEq, Debug, Clone)]
+pub struct MemoryImage {
+    mailboxes: Vec<u16>,
+}
+
+impl MemoryImage {
+    /// Creates a memory image from the machine code for each mailbox, starting at mailbox 0.
+    /// There can be at most [`MAILBOXES`] of them, which [`assemble`] checks before creating one.
+    pub(crate) fn new(mailboxes: Vec<u16>) -> Self {
+        Self { mailboxes }
+    }
+
+    /// Gets the machine code for each mailbox used by the program, starting at mailbox 0
+    pub fn get_mailboxes(&self) -> &[u16] {
+        &self.mailboxes
+    }
+
+    /// Gets the contents of all mailboxes, with any not used by the program set to 0
+    pub fn to_memory(&self) -> [u16; MAILBOXES] {
+        let mut memory = [0; MAILBOXES];
+        memory[..self.mailboxes.len()].copy_from_slice(&self.mailboxes);
+
+        memory
+    }
+
+    /// Formats the image as a comma separated list of numbers, such as "901, 902, 0"
+    pub fn to_list(&self) -> String {
+        self.mailboxes
+            .iter()
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Display for MemoryImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (address, code) in self.mailboxes.iter().enumerate() {
+            writeln!(f, "{:02}\t{:03}", address, code)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Assembles an expanded program into machine code, placing each instruction in the next mailbox
+/// and replacing labels with the address of the instruction they are attached to.
+pub(crate) fn assemble(program: &[Item]) -> Result<MemoryImage, Vec<Diagnostic>> {
+    let instructions: Vec<_> = program
+        .iter()
+        .filter_map(|item| match item {
+            Item::Instruction(instruction) => Some(instruction),
+            _ => None,
+        })
+        .collect();
+
+    if instructions.len() > MAILBOXES {
+        return Err(vec![Diagnostic::new(
+            format!(
+                "program needs {} mailboxes, but only {} are available",
+                instructions.len(),
+                MAILBOXES
+            ),
+            instructions[MAILBOXES].get_span(),
+        )]);
+    }
+
+    // first find the address of every label, then encode each instruction now that labels can be resolved
+    let (labels, mut errors) = find_labels(&instructions);
+    let mailboxes = instructions
+        .iter()
+        .map(|instruction| {
+            encode(instruction, &labels).unwrap_or_else(|error| {
+                errors.push(error);
+                0
+            })
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(MemoryImage::new(mailboxes))
+    } else {
+        Err(errors)
+    }
+}
+
+/// Replaces every operand which is an expression with its value, once labels have been given addresses,
+/// so the program can be run by simulators which only accept numbers and labels as operands.
+/// Values which are out of range for their opcode are reported the same way as when assembling.
+pub(crate) fn resolve_expressions<'a>(
+    program: &[Item<'a>],
+) -> Result<Vec<Item<'a>>, Vec<Diagnostic>> {
+    let instructions: Vec<_> = program
+        .iter()
+        .filter_map(|item| match item {
+            Item::Instruction(instruction) => Some(instruction),
+            _ => None,
+        })
+        .collect();
+    let (labels, mut errors) = find_labels(&instructions);
+
+    let program = program
+        .iter()
+        .map(|item| match item {
+            Item::Instruction(instruction) => {
+                // labels are left as they are, while numbers are only checked as they are already values
+                let resolved = match (instruction.get_opcode(), instruction.get_operand()) {
+                    (Some(opcode), Some(operand)) if is_expression(operand) => {
+                        evaluate_operand(instruction, opcode, operand, &labels).map(|value| {
+                            instruction.clone_with_resolved_operand(value.to_string().into())
+                        })
+                    }
+                    (Some(opcode), Some(operand))
+                        if operand.chars().all(|c| c.is_ascii_digit()) =>
+                    {
+                        evaluate_operand(instruction, opcode, operand, &labels)
+                            .map(|_| instruction.clone())
+                    }
+                    _ => Ok(instruction.clone()),
+                };
+
+                Item::Instruction(resolved.unwrap_or_else(|error| {
+                    errors.push(error);
+                    instruction.clone()
+                }))
+            }
+            _ => item.clone(),
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Finds the address of every label, which is the mailbox of the instruction it is attached to,
+/// along with an error for each label defined more than once
+fn find_labels<'p>(instructions: &[&'p Instruction]) -> (HashMap<&'p str, u16>, Vec<Diagnostic>) {
+    let mut errors = Vec::new();
+
+    let mut labels: HashMap<&str, (u16, &Instruction)> = HashMap::new();
+    for (address, instruction) in instructions.iter().enumerate() {
+        if let Some(label) = instruction.get_label() {
+            if let Some((_, first)) = labels.insert(label, (address as u16, instruction)) {
+                errors.push(
+                    Diagnostic::new(
+                        format!("label `{}` is defined more than once", label),
+                        instruction.get_span(),
+                    )
+                    .with_note("first defined here", first.get_span()),
+                );
+            }
+        }
+    }
+
+    let labels = labels
+        .into_iter()
+        .map(|(label, (address, _))| (label, address))
+        .collect();
+
+    (labels, errors)
+}
+
+/// Gets the machine code for an opcode, along with the largest value its operand can have if it takes one
+fn opcode_encoding(opcode: &Opcode) -> (u16, Option<u16>) {
+    match opcode {
+        Opcode::ADD => (100, Some(99)),
+        Opcode::SUB => (200, Some(99)),
+        Opcode::STO => (300, Some(99)),
+        Opcode::LDA => (500, Some(99)),
+        Opcode::BR => (600, Some(99)),
+        Opcode::BRZ => (700, Some(99)),
+        Opcode::BRP => (800, Some(99)),
+        Opcode::IN => (901, None),
+        Opcode::OUT => (902, None),
+        Opcode::OTC => (922, None),
+        Opcode::HLT => (0, None),
+        Opcode::DAT => (0, Some(999)),
+    }
+}
+
+/// Encodes a single instruction as machine code
+fn encode(instruction: &Instruction, labels: &HashMap<&str, u16>) -> Result<u16, Diagnostic> {
+    let opcode = instruction.get_opcode().ok_or_else(|| {
+        Diagnostic::new(
+            format!("`{}` is not a valid opcode", instruction.get_operation()),
+            instruction.get_span(),
+        )
+    })?;
+    let (code, max_operand) = opcode_encoding(opcode);
+
+    let operand = match (instruction.get_operand(), max_operand) {
+        (Some(operand), Some(_)) => evaluate_operand(instruction, opcode, operand, labels)?,
+        (Some(_), None) => {
+            return Err(Diagnostic::new(
+                format!("`{}` does not take an operand", opcode),
+                instruction.get_span(),
+            ))
+        }
+        // data defaults to 0 if no value is given
+        (None, Some(_)) if *opcode == Opcode::DAT => 0,
+        (None, Some(_)) => {
+            return Err(Diagnostic::new(
+                format!("`{}` needs an operand", opcode),
+                instruction.get_span(),
+            ))
+        }
+        (None, None) => 0,
+    };
+
+    Ok(code + operand)
+}
+
+/// Evaluates the operand of an instruction whose opcode takes one, checking it is in range for the opcode.
+/// Operands are a number, a label to be replaced with its address, or an expression made of both.
+fn evaluate_operand(
+    instruction: &Instruction,
+    opcode: &Opcode,
+    operand: &str,
+    labels: &HashMap<&str, u16>,
+) -> Result<u16, Diagnostic> {
+    let max_operand = match opcode_encoding(opcode) {
+        (_, Some(max_operand)) => max_operand,
+        _ => {
+            return Err(Diagnostic::new(
+                format!("`{}` does not take an operand", opcode),
+                instruction.get_span(),
+            ))
+        }
+    };
+
+    let value = evaluate(operand, |label| {
+        labels
+            .get(label)
+            .map(|&address| address as i64)
+            .ok_or_else(|| format!("undefined label `{}`", label))
+    })
+    .map_err(|message| Diagnostic::new(message, instruction.get_span()))?;
+
+    // errors show the operand as it was written, which can name constants that have been replaced
+    if !(0..=max_operand as i64).contains(&value) {
+        let written = instruction.get_written_operand().unwrap_or(operand);
+        let message = if written.chars().all(|c| c.is_ascii_digit()) {
+            format!(
+                "operand `{}` is out of range, expected a value from 0 to {}",
+                written, max_operand
+            )
+        } else {
+            format!(
+                "operand `{}` evaluates to {}, which is out of range, expected a value from 0 to {}",
+                written, value, max_operand
+            )
+        };
+        return Err(Diagnostic::new(message, instruction.get_span()));
+    }
+
+    Ok(value as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn test_assemble() {
+        let program = parse(
+            "   IN
+                STO a
+                IN
+                STO b
+            start LDA count
+                ADD one
+                STO count
+                LDA a
+                SUB b
+                STO a
+                BRP start
+            done LDA count
+                SUB one
+                OUT
+                HLT
+            a DAT
+            b DAT 000
+            count DAT 000
+            one DAT 001
+                OTC",
+        )
+        .unwrap();
+
+        assert_eq!(
+            assemble(&program).unwrap().get_mailboxes(),
+            &[
+                901, 315, 901, 316, 517, 118, 317, 515, 216, 315, 804, 517, 218, 902, 0, 0, 0, 0,
+                1, 922
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        macro_rules! assert_assemble_error {
+            ($($input:expr => $message:expr;)*) => {
+                $(
+                    let program = parse($input).unwrap();
+                    let errors = assemble(&program).unwrap_err();
+                    assert_eq!(errors[0].get_message(), $message);
+                )*
+            };
+        }
+
+        let too_long = "OUT\n".repeat(101);
+        assert_assemble_error!(
+            "LDA missing" => "undefined label `missing`";
+            "a DAT 1\na DAT 2" => "label `a` is defined more than once";
+            "LDA 100" => "operand `100` is out of range, expected a value from 0 to 99";
+            "DAT 1000" => "operand `1000` is out of range, expected a value from 0 to 999";
+            "OUT 5" => "`OUT` does not take an operand";
+            "a LDA a+100" => "operand `a+100` evaluates to 100, which is out of range, expected a value from 0 to 99";
+            "a DAT a-1" => "operand `a-1` evaluates to -1, which is out of range, expected a value from 0 to 999";
+            "LDA b+1" => "undefined label `b`";
+            "ADD" => "`ADD` needs an operand";
+            &too_long => "program needs 101 mailboxes, but only 100 are available";
+        );
+    }
+
+    #[test]
+    fn test_assemble_expressions() {
+        let program = parse(
+            "   LDA table+2
+                STO table+end-start
+                BR end-1
+            start DAT 5*(2+1)
+            table DAT
+                DAT
+            end DAT -(-1)",
+        )
+        .unwrap();
+
+        assert_eq!(
+            assemble(&program).unwrap().get_mailboxes(),
+            &[506, 307, 605, 15, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn test_resolve_expressions() {
+        let program = parse(
+            "   LDA table+(3-1)*2
+                BR end
+            table DAT 5*(2+1)
+            end DAT -(-1)",
+        )
+        .unwrap();
+
+        let program = resolve_expressions(&program).unwrap();
+        assert_eq!(
+            crate::render(&program, Default::default()),
+            "\tLDA\t6\n\tBR\tend\ntable\tDAT\t15\nend\tDAT\t1\n"
+        );
+
+        let program = parse("t LDA t+200").unwrap();
+        let errors = resolve_expressions(&program).unwrap_err();
+        assert_eq!(
+            errors[0].get_message(),
+            "operand `t+200` evaluates to 200, which is out of range, expected a value from 0 to 99"
+        );
+
+        // numbers are checked too, and errors show constants by name rather than by value
+        let program = parse("const LIMIT = 500\nLDA LIMIT\nLDA LIMIT+0\nLDA 100").unwrap();
+        let program = crate::expand(&program, &Default::default()).unwrap();
+        let errors = resolve_expressions(&program).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(Diagnostic::get_message)
+                .collect::<Vec<_>>(),
+            vec![
+                "operand `LIMIT` evaluates to 500, which is out of range, expected a value from 0 to 99",
+                "operand `LIMIT+0` evaluates to 500, which is out of range, expected a value from 0 to 99",
+                "operand `100` is out of range, expected a value from 0 to 99"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_memory_image_formats() {
+        let image = MemoryImage::new(vec![901, 902, 0]);
+
+        assert_eq!(image.to_string(), "00\t901\n01\t902\n02\t000\n");
+        assert_eq!(image.to_list(), "901, 902, 0");
+        assert_eq!(image.to_memory()[..4], [901, 902, 0, 0]);
+    }
+}
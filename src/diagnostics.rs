@@ -0,0 +1,298 @@
+use std::fmt::{self, Display, Formatter};
+
+/// A region of source text that an item was parsed from.
+///
+/// Spans store the address of the text rather than borrowing it, so they can be freely copied into diagnostics;
+/// a [`SourceMap`] holding the original text is needed to turn one back into a file, line and column.
+/// Spans are ignored when comparing, so items compare equal regardless of where they were parsed.
+#[derive(Clone, Copy, Debug, Default, Eq)]
+pub struct Span {
+    start: usize,
+    len: usize,
+}
+
+impl Span {
+    /// Creates a span covering the given fragment of source text
+    pub(crate) fn of(fragment: &str) -> Self {
+        Self {
+            start: fragment.as_ptr() as usize,
+            len: fragment.len(),
+        }
+    }
+
+    /// Returns true if the span does not point at any source text
+    pub fn is_empty(&self) -> bool {
+        self.start == 0
+    }
+
+    /// Returns true if both spans cover exactly the same text, unlike `==` which ignores spans entirely
+    pub(crate) fn same_as(&self, other: &Span) -> bool {
+        self.start == other.start && self.len == other.len
+    }
+}
+
+impl PartialEq for Span {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+/// A single named piece of source text, such as a file
+#[derive(Debug)]
+struct Source {
+    name: String,
+    text: String,
+}
+
+/// Stores all source text given to the preprocessor, so that spans can be traced back to where they came from.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    sources: Vec<Source>,
+}
+
+impl SourceMap {
+    /// Creates an empty source map
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a new source with the given name, returning an id that can be used to get its text
+    pub fn add(&mut self, name: impl Into<String>, text: impl Into<String>) -> usize {
+        self.sources.push(Source {
+            name: name.into(),
+            text: text.into(),
+        });
+
+        self.sources.len() - 1
+    }
+
+    /// Gets the text of the source with the given id
+    pub fn get(&self, id: usize) -> &str {
+        &self.sources[id].text
+    }
+
+    /// Gets the name of the source with the given id, such as the path of the file it was read from
+    pub fn get_name(&self, id: usize) -> &str {
+        &self.sources[id].name
+    }
+
+    /// Finds the file, line and column the given span starts at
+    pub fn locate(&self, span: Span) -> Option<Location<'_>> {
+        // find the source whose text contains the span
+        let source = self.sources.iter().find(|source| {
+            let start = source.text.as_ptr() as usize;
+            (start..=start + source.text.len()).contains(&span.start)
+        })?;
+        let offset = span.start - source.text.as_ptr() as usize;
+
+        let line_start = source.text[..offset]
+            .rfind('\n')
+            .map_or(0, |index| index + 1);
+        let line_end = source.text[offset..]
+            .find('\n')
+            .map_or(source.text.len(), |index| offset + index);
+
+        Some(Location {
+            file: &source.name,
+            line: source.text[..offset].matches('\n').count() + 1,
+            column: source.text[line_start..offset].chars().count() + 1,
+            snippet: source.text[line_start..line_end].trim_end_matches('\r'),
+            length: span.len.min(line_end - offset),
+        })
+    }
+}
+
+/// The position of a span within its source
+#[derive(PartialEq, Debug, Clone)]
+pub struct Location<'a> {
+    /// Name of the source the span is from
+    pub file: &'a str,
+    /// Line number, starting at 1
+    pub line: usize,
+    /// Column number, starting at 1
+    pub column: usize,
+    /// The full line of source text containing the start of the span
+    pub snippet: &'a str,
+    /// Length of the span within the snippet
+    pub length: usize,
+}
+
+/// An additional message attached to a diagnostic, optionally pointing at another location
+#[derive(PartialEq, Debug, Clone)]
+pub struct Note {
+    message: String,
+    span: Span,
+}
+
+/// How serious a diagnostic is
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Severity {
+    /// The program can't be preprocessed
+    Error,
+    /// The program can be preprocessed, but probably doesn't do what was intended
+    Warning,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// An error or warning found while preprocessing a program
+#[derive(PartialEq, Debug, Clone)]
+pub struct Diagnostic {
+    message: String,
+    span: Span,
+    severity: Severity,
+    notes: Vec<Note>,
+}
+
+impl Diagnostic {
+    /// Creates a new error with the given message, pointing at the given span
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            message: message.into(),
+            span,
+            severity: Severity::Error,
+            notes: Vec::new(),
+        }
+    }
+
+    /// Creates a new warning with the given message, pointing at the given span
+    pub fn warning(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::new(message, span)
+        }
+    }
+
+    /// Adds a note to the diagnostic, pointing at the given span
+    pub fn with_note(mut self, message: impl Into<String>, span: Span) -> Self {
+        self.notes.push(Note {
+            message: message.into(),
+            span,
+        });
+        self
+    }
+
+    /// Gets the diagnostic's message
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    /// Gets the span the diagnostic points at
+    pub fn get_span(&self) -> Span {
+        self.span
+    }
+
+    /// Gets whether the diagnostic is an error or a warning
+    pub fn get_severity(&self) -> Severity {
+        self.severity
+    }
+
+    /// Returns true if both diagnostics have the same message and point at the same text
+    pub(crate) fn same_as(&self, other: &Diagnostic) -> bool {
+        self.message == other.message && self.span.same_as(&other.span)
+    }
+
+    /// Renders the diagnostic along with the source it points at, such as
+    /// ```text
+    /// error: unknown opcode `ADDD`
+    ///  --> program.asm:3:5
+    ///   |
+    /// 3 |     ADDD 10
+    ///   |     ^^^^
+    /// ```
+    pub fn render(&self, sources: &SourceMap) -> String {
+        let mut output = format!("{}: {}\n", self.severity, self.message);
+        render_span(&mut output, sources, self.span);
+
+        for note in &self.notes {
+            output += &format!("note: {}\n", note.message);
+            render_span(&mut output, sources, note.span);
+        }
+
+        output
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Renders the location and snippet of a span, if it can be found
+fn render_span(output: &mut String, sources: &SourceMap, span: Span) {
+    let location = match sources.locate(span) {
+        Some(location) => location,
+        None => return,
+    };
+
+    let line = location.line.to_string();
+    let padding = " ".repeat(line.len());
+    // keep tabs in the snippet so the markers line up with it
+    let indent: String = location
+        .snippet
+        .chars()
+        .take(location.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    *output += &format!(
+        "{}--> {}:{}:{}\n{} |\n{} | {}\n{} | {}{}\n",
+        padding,
+        location.file,
+        location.line,
+        location.column,
+        padding,
+        line,
+        location.snippet,
+        padding,
+        indent,
+        "^".repeat(location.length.max(1))
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let mut sources = SourceMap::new();
+        let id = sources.add("test.asm", "IN\n\tSTO a\nOUT");
+        let text = sources.get(id);
+
+        assert_eq!(
+            sources.locate(Span::of(&text[4..7])),
+            Some(Location {
+                file: "test.asm",
+                line: 2,
+                column: 2,
+                snippet: "\tSTO a",
+                length: 3,
+            })
+        );
+        assert_eq!(sources.locate(Span::default()), None);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut sources = SourceMap::new();
+        let id = sources.add("test.asm", "IN\nADDD 10");
+        let text = sources.get(id);
+
+        assert_eq!(
+            Diagnostic::new("unknown opcode `ADDD`", Span::of(&text[3..7])).render(&sources),
+            "error: unknown opcode `ADDD`\n --> test.asm:2:1\n  |\n2 | ADDD 10\n  | ^^^^\n"
+        );
+        assert!(Diagnostic::warning("unused", Span::of(&text[3..7]))
+            .render(&sources)
+            .starts_with("warning: unused\n"));
+    }
+}
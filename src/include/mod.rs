@@ -0,0 +1,613 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    diagnostics::{Diagnostic, SourceMap, Span},
+    parser::{self, dialect::Dialect, Item},
+};
+
+/// Options controlling where included files are found
+#[derive(Debug, Clone, Default)]
+pub struct IncludeOptions {
+    /// Directories searched for included files, in order, if a file isn't found next to the file including it
+    pub search_paths: Vec<PathBuf>,
+}
+
+/// The modules of the standard library, which are built into the preprocessor and loaded with `use`
+const LIBRARY: &[(&str, &str)] = &[
+    ("std::math", include_str!("std/math.asm")),
+    ("std::wide", include_str!("std/wide.asm")),
+];
+
+/// Parses the source with the given id, replacing each `include` with the contents of the file it names
+/// and each `use` with the module of the standard library it names.
+/// Every included file and module is added to the source map, so diagnostics can point into it.
+/// Conditions haven't been evaluated yet, so includes are resolved even inside branches that end up left out.
+pub(crate) fn load<'a>(
+    sources: &'a mut SourceMap,
+    id: usize,
+    dialect: Dialect,
+    options: &IncludeOptions,
+) -> Result<Vec<Item<'a>>, Vec<Diagnostic>> {
+    let mut loader = Loader {
+        dialect,
+        options,
+        loaded: HashMap::new(),
+        modules: HashMap::new(),
+        resolved: HashMap::new(),
+        errors: Vec::new(),
+    };
+
+    // the source itself is part of the chain, so including it again is a cycle
+    let name = sources.get_name(id).to_string();
+    let path = Path::new(&name);
+    let mut chain = vec![(
+        path.canonicalize().unwrap_or_else(|_| path.to_path_buf()),
+        name,
+    )];
+    loader.load(sources, id, &mut chain);
+
+    if !loader.errors.is_empty() {
+        return Err(loader.errors);
+    }
+
+    // now every file is loaded, the source map no longer needs to change so the items can borrow from it
+    let sources: &'a SourceMap = sources;
+    let mut program = Vec::new();
+    splice(sources, id, &loader, &mut program)?;
+
+    Ok(program)
+}
+
+/// Stores the state needed while loading included files
+struct Loader<'o> {
+    dialect: Dialect,
+    options: &'o IncludeOptions,
+    /// The id of each file which has been loaded, by its canonical path
+    loaded: HashMap<PathBuf, usize>,
+    /// The id of each module of the standard library which has been loaded, by its name
+    modules: HashMap<String, usize>,
+    /// The id of the file or module each include or use resolved to, by the id of the including file and the path it named
+    resolved: HashMap<(usize, String), usize>,
+    errors: Vec<Diagnostic>,
+}
+
+impl<'o> Loader<'o> {
+    /// Loads every file included by the given source, and everything they include,
+    /// where chain is the list of files currently being loaded along with their names
+    fn load(&mut self, sources: &mut SourceMap, id: usize, chain: &mut Vec<(PathBuf, String)>) {
+        // only the paths are needed, so they are copied out to leave the source map free to change
+        let (includes, uses): (Vec<_>, Vec<_>) =
+            match parser::parse(sources.get(id), self.dialect_of(id)) {
+                Ok(program) => program
+                    .iter()
+                    .filter_map(|item| match item {
+                        Item::Include(path, span) => Some((true, path.to_string(), *span)),
+                        Item::Use(module, span) => Some((false, module.to_string(), *span)),
+                        _ => None,
+                    })
+                    .partition(|(include, _, _)| *include),
+                Err(error) => return self.errors.push(error),
+            };
+
+        for (_, module, span) in uses {
+            let used = match self.modules.get(&module) {
+                Some(used) => *used,
+                None => match LIBRARY.iter().find(|(name, _)| *name == module) {
+                    Some((name, text)) => {
+                        let used = sources.add(*name, *text);
+                        self.modules.insert(module.clone(), used);
+                        // modules can use each other, but are only loaded once so can't form a cycle
+                        self.load(sources, used, chain);
+
+                        used
+                    }
+                    None => {
+                        let names: Vec<_> = LIBRARY
+                            .iter()
+                            .map(|(name, _)| format!("`{}`", name))
+                            .collect();
+                        self.errors.push(
+                            Diagnostic::new(format!("unknown library module `{}`", module), span)
+                                .with_note(
+                                    format!("the standard library has {}", names.join(", ")),
+                                    Span::default(),
+                                ),
+                        );
+                        continue;
+                    }
+                },
+            };
+
+            self.resolved.insert((id, module), used);
+        }
+
+        let directory = Path::new(sources.get_name(id))
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+
+        for (_, path, span) in includes {
+            let found = std::iter::once(&directory)
+                .chain(&self.options.search_paths)
+                .map(|directory| directory.join(&path))
+                .find(|candidate| candidate.is_file());
+            let found = match found {
+                Some(found) => found,
+                None => {
+                    self.errors.push(Diagnostic::new(
+                        format!("could not find included file `{}`", path),
+                        span,
+                    ));
+                    continue;
+                }
+            };
+            let canonical = found.canonicalize().unwrap_or_else(|_| found.clone());
+
+            if chain.iter().any(|(loading, _)| *loading == canonical) {
+                self.errors.push(include_cycle(chain, &found, span));
+                continue;
+            }
+
+            let included = match self.loaded.get(&canonical) {
+                Some(included) => *included,
+                None => match std::fs::read_to_string(&found) {
+                    Ok(text) => {
+                        let name = found.to_string_lossy().to_string();
+                        let included = sources.add(name.clone(), text);
+                        self.loaded.insert(canonical.clone(), included);
+
+                        chain.push((canonical, name));
+                        self.load(sources, included, chain);
+                        chain.pop();
+
+                        included
+                    }
+                    Err(error) => {
+                        self.errors.push(Diagnostic::new(
+                            format!("could not read included file `{}`: {}", path, error),
+                            span,
+                        ));
+                        continue;
+                    }
+                },
+            };
+
+            self.resolved.insert((id, path), included);
+        }
+    }
+
+    /// Returns true if the source with the given id is a module of the standard library
+    fn is_library(&self, id: usize) -> bool {
+        self.modules.values().any(|&module| module == id)
+    }
+
+    /// Gets the dialect the source with the given id is written in,
+    /// where the standard library is written so that it can be used from any dialect
+    fn dialect_of(&self, id: usize) -> Dialect {
+        if self.is_library(id) {
+            Dialect::default()
+        } else {
+            self.dialect
+        }
+    }
+}
+
+/// Parses the source with the given id into the program, recursively replacing each include with its file's items
+/// and each use with its module's items.
+/// A module is added every time it is used, as a use can be in a branch that is left out,
+/// and the copies are the same declarations so only one of them is kept when macros are expanded.
+fn splice<'a>(
+    sources: &'a SourceMap,
+    id: usize,
+    loader: &Loader,
+    program: &mut Vec<Item<'a>>,
+) -> Result<(), Vec<Diagnostic>> {
+    let library = loader.is_library(id);
+    for item in
+        parser::parse(sources.get(id), loader.dialect_of(id)).map_err(|error| vec![error])?
+    {
+        match item {
+            Item::Include(path, _) => splice(
+                sources,
+                loader.resolved[&(id, path.to_string())],
+                loader,
+                program,
+            )?,
+            Item::Use(module, _) => splice(
+                sources,
+                loader.resolved[&(id, module.to_string())],
+                loader,
+                program,
+            )?,
+            // the library's own comments aren't copied into the program
+            Item::Comment(..) if library => {}
+            item => program.push(item),
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates an error for a file which ends up including itself, showing the full chain of includes that led to it
+fn include_cycle(chain: &[(PathBuf, String)], found: &Path, span: Span) -> Diagnostic {
+    let chain: Vec<_> = chain
+        .iter()
+        .map(|(_, name)| name.clone())
+        .chain(std::iter::once(found.to_string_lossy().to_string()))
+        .collect();
+
+    Diagnostic::new(format!("include cycle: {}", chain.join(" → ")), span)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Output;
+
+    /// A temporary directory, which is removed along with everything in it once dropped
+    struct TempDirectory(PathBuf);
+
+    impl std::ops::Deref for TempDirectory {
+        type Target = PathBuf;
+
+        fn deref(&self) -> &PathBuf {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDirectory {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Creates a temporary directory containing the given files
+    fn directory(name: &str, files: &[(&str, &str)]) -> TempDirectory {
+        let directory = TempDirectory(std::env::temp_dir().join(format!(
+            "lmc-preprocessor-{}-{}",
+            name,
+            std::process::id()
+        )));
+
+        for (path, text) in files {
+            let path = directory.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, text).unwrap();
+        }
+
+        directory
+    }
+
+    /// Loads the given file, returning the rendered program or the location and message of each error
+    fn load_file(path: PathBuf, options: &IncludeOptions) -> Result<String, Vec<String>> {
+        let mut sources = SourceMap::new();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let id = sources.add(path.to_string_lossy(), text);
+
+        let result = load(&mut sources, id, Dialect::default(), options)
+            .map(|program| crate::render(&program, Default::default()));
+        result.map_err(|errors| {
+            errors
+                .iter()
+                .map(|error| {
+                    let location = sources.locate(error.get_span()).unwrap();
+                    let file = Path::new(location.file).file_name().unwrap();
+                    format!("{}:{}: {}", file.to_string_lossy(), location.line, error)
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_include() {
+        let directory = directory(
+            "include",
+            &[
+                (
+                    "main.asm",
+                    "include \"lib/io.asm\"\ninclude \"math.asm\"\nHLT",
+                ),
+                ("lib/io.asm", "IN\ninclude \"out.asm\""),
+                ("lib/out.asm", "OUT"),
+                ("shared/math.asm", "ADD one"),
+            ],
+        );
+
+        let options = IncludeOptions {
+            search_paths: vec![directory.join("shared")],
+        };
+        assert_eq!(
+            load_file(directory.join("main.asm"), &options),
+            Ok("\tIN\n\tOUT\n\tADD\tone\n\tHLT\n".to_string())
+        );
+        assert_eq!(
+            load_file(directory.join("main.asm"), &IncludeOptions::default()),
+            Err(vec![
+                "main.asm:2: could not find included file `math.asm`".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_include_twice() {
+        let directory = directory(
+            "include-twice",
+            &[
+                ("a.asm", "include \"b.asm\"\ninclude \"c.asm\"\nM!()"),
+                ("b.asm", "include \"m.asm\""),
+                ("c.asm", "include \"m.asm\""),
+                ("m.asm", "macro M() = {\n OUT\n}"),
+            ],
+        );
+
+        // both includes of m.asm give the same declaration, so the call isn't ambiguous
+        let mut sources = SourceMap::new();
+        let path = directory.join("a.asm");
+        let id = sources.add(
+            path.to_string_lossy(),
+            std::fs::read_to_string(&path).unwrap(),
+        );
+        let program = load(&mut sources, id, Dialect::default(), &Default::default()).unwrap();
+        let program = crate::expand(&program, &Default::default()).unwrap();
+        assert_eq!(crate::render(&program, Default::default()), "\tOUT\n");
+    }
+
+    /// Loads the program from text, then assembles and runs it with the given input, returning everything it outputs
+    fn run(program: &str, input: Vec<u16>) -> Vec<Output> {
+        let mut sources = SourceMap::new();
+        let id = sources.add("test.asm", program);
+        let program = load(&mut sources, id, Dialect::default(), &Default::default()).unwrap();
+        let image =
+            crate::assemble(&crate::expand(&program, &Default::default()).unwrap()).unwrap();
+
+        let mut output = Vec::new();
+        crate::Machine::new(&image)
+            .run(input, 100_000, |value| output.push(value))
+            .unwrap();
+        output
+    }
+
+    /// Gets the output of a program which only outputs the given numbers
+    fn numbers(values: &[u16]) -> Vec<Output> {
+        values.iter().copied().map(Output::Number).collect()
+    }
+
+    #[test]
+    fn test_std_math() {
+        // each macro is used twice, so they need their own labels and storage for each expansion
+        let arithmetic = "use std::math
+            use std::math
+            IN
+            STO a
+            IN
+            STO b
+            MULTIPLY!(a, b, r)
+            OUT
+            MULTIPLY!(r, b, r)
+            OUT
+            DIVIDE!(a, b, r)
+            OUT
+            MOD!(a, b, r)
+            OUT
+            HLT
+            a DAT
+            b DAT
+            r DAT";
+        assert_eq!(run(arithmetic, vec![17, 5]), numbers(&[85, 425, 3, 2]));
+        assert_eq!(run(arithmetic, vec![9, 0]), numbers(&[0, 0, 0, 9]));
+
+        let signs = "use std::math
+            IN
+            STO a
+            NEGATE!(a, r)
+            OUT
+            ABS!(r, r)
+            OUT
+            ABS!(a, r)
+            OUT
+            HLT
+            a DAT
+            r DAT";
+        assert_eq!(run(signs, vec![7]), numbers(&[993, 7, 7]));
+        assert_eq!(run(signs, vec![0]), numbers(&[0, 0, 0]));
+
+        let comparisons = "use std::math
+            IN
+            STO a
+            IN
+            STO b
+            MAX!(a, b, r)
+            OUT
+            MIN!(a, b, r)
+            OUT
+            SWAP!(a, b)
+            LDA a
+            OUT
+            COMPARE!(a, b, less, equal, greater)
+            less LDA one
+            BR done
+            equal LDA two
+            BR done
+            greater LDA three
+            done OUT
+            HLT
+            a DAT
+            b DAT
+            r DAT
+            one DAT 1
+            two DAT 2
+            three DAT 3";
+        assert_eq!(run(comparisons, vec![4, 9]), numbers(&[9, 4, 9, 3]));
+        assert_eq!(run(comparisons, vec![9, 4]), numbers(&[9, 4, 4, 1]));
+        assert_eq!(run(comparisons, vec![6, 6]), numbers(&[6, 6, 6, 2]));
+        // 999 is -1, so is smaller than 1
+        assert_eq!(run(comparisons, vec![999, 1]), numbers(&[1, 999, 1, 3]));
+    }
+
+    #[test]
+    fn test_std_wide() {
+        let arithmetic = "use std::wide
+            IN
+            STO ah
+            IN
+            STO al
+            IN
+            STO bh
+            IN
+            STO bl
+            WIDE_ADD!(ah, al, bh, bl, rh, rl)
+            WIDE_OUT!(rh, rl)
+            WIDE_SUB!(ah, al, bh, bl, rh, rl)
+            WIDE_OUT!(rh, rl)
+            HLT
+            ah DAT
+            al DAT
+            bh DAT
+            bl DAT
+            rh DAT
+            rl DAT";
+
+        // 999 + 1 carries into the high part, and 999 - 1 doesn't need to borrow
+        assert_eq!(
+            run(arithmetic, vec![0, 999, 0, 1]),
+            numbers(&[1, 0, 0, 998])
+        );
+        // 123456 + 654 = 124110, and 123456 - 654 = 122802 borrows from the high part
+        assert_eq!(
+            run(arithmetic, vec![123, 456, 0, 654]),
+            numbers(&[124, 110, 122, 802])
+        );
+        // 5 + 7 only has a low part, while 5 - 7 wraps around to 999998, which is -2
+        assert_eq!(
+            run(arithmetic, vec![0, 5, 0, 7]),
+            numbers(&[0, 12, 999, 998])
+        );
+
+        let comparison = "use std::wide
+            IN
+            STO ah
+            IN
+            STO al
+            IN
+            STO bh
+            IN
+            STO bl
+            WIDE_COMPARE!(ah, al, bh, bl, less, equal, greater)
+            less LDA one
+            BR done
+            equal LDA two
+            BR done
+            greater LDA three
+            done OUT
+            HLT
+            ah DAT
+            al DAT
+            bh DAT
+            bl DAT
+            one DAT 1
+            two DAT 2
+            three DAT 3";
+        assert_eq!(run(comparison, vec![1, 0, 0, 999]), numbers(&[3]));
+        assert_eq!(run(comparison, vec![2, 5, 2, 7]), numbers(&[1]));
+        assert_eq!(run(comparison, vec![2, 5, 2, 5]), numbers(&[2]));
+        // 999 999 is -1, so is smaller than 0
+        assert_eq!(run(comparison, vec![999, 999, 0, 0]), numbers(&[1]));
+        assert_eq!(run(comparison, vec![0, 0, 999, 999]), numbers(&[3]));
+    }
+
+    #[test]
+    fn test_use_in_left_out_branch() {
+        // the module is still added by the second use, as the first is thrown away with its branch
+        let program = "ifdef DEBUG
+            use std::math
+            endif
+            use std::math
+            IN
+            STO a
+            IN
+            STO b
+            SWAP!(a, b)
+            LDA a
+            OUT
+            HLT
+            a DAT
+            b DAT";
+        assert_eq!(run(program, vec![1, 2]), numbers(&[2]));
+    }
+
+    #[test]
+    fn test_unknown_module() {
+        let mut sources = SourceMap::new();
+        let id = sources.add("test.asm", "use std::maths");
+        let errors = load(&mut sources, id, Dialect::default(), &Default::default()).unwrap_err();
+
+        assert_eq!(
+            errors[0].get_message(),
+            "unknown library module `std::maths`"
+        );
+    }
+
+    #[test]
+    fn test_library_dialects() {
+        let program = "use std::math\nuse std::wide\nMAX!(a, b, a)\nWIDE_OUT!(a, b)\na DAT\nb DAT";
+        let expand = |dialect: Dialect| {
+            let mut sources = SourceMap::new();
+            let id = sources.add("test.asm", program);
+            let program = load(&mut sources, id, dialect, &Default::default()).unwrap();
+            let options = crate::ExpandOptions {
+                dialect,
+                ..Default::default()
+            };
+            crate::expand(&program, &options)
+                .map(|_| ())
+                .map_err(|errors| {
+                    errors
+                        .iter()
+                        .map(|error| error.get_message().to_string())
+                        .collect::<Vec<_>>()
+                })
+        };
+
+        // the library only uses opcodes which every dialect has
+        for dialect in [
+            Dialect::any(),
+            Dialect::durham(),
+            Dialect::higginson(),
+            Dialect::madnick(),
+        ] {
+            assert_eq!(expand(dialect), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_include_errors() {
+        let directory = directory(
+            "include-errors",
+            &[
+                ("a.asm", "IN\ninclude \"b.asm\""),
+                ("b.asm", "OUT\ninclude \"a.asm\""),
+                ("c.asm", "include \"d.asm\""),
+                ("d.asm", "IN\nADDD 1"),
+            ],
+        );
+
+        let errors = load_file(directory.join("a.asm"), &IncludeOptions::default()).unwrap_err();
+        let prefix = format!("{}/", directory.to_string_lossy());
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.replace(&prefix, ""))
+                .collect::<Vec<_>>(),
+            vec!["b.asm:2: include cycle: a.asm → b.asm → a.asm"]
+        );
+
+        // errors in an included file point into that file
+        assert_eq!(
+            load_file(directory.join("c.asm"), &IncludeOptions::default()),
+            Err(vec!["d.asm:2: unknown opcode `ADDD`".to_string()])
+        );
+    }
+}
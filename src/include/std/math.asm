@@ -0,0 +1,132 @@
+# std::math - arithmetic on values from 0 to 999
+# Negative values are stored in ten's complement, so -1 is 999, the same as `SUB` wraps around to.
+# Each macro leaves its result in the accumulator as well as storing it.
+
+# $result = $a * $b, wrapping around past 999
+macro MULTIPLY($a, $b, $result) = {
+    local $count
+    local $total
+    local $zero
+    local $one = 1
+    LDA $b
+    STO $count
+    LDA $zero
+    STO $total
+    loop LDA $count
+    BRZ done
+    SUB $one
+    STO $count
+    LDA $total
+    ADD $a
+    STO $total
+    BR loop
+    done LDA $total
+    STO $result
+}
+
+# $quotient = $a / $b and $remainder = $a mod $b, where dividing by zero gives 0 remainder $a
+macro DIVMOD($a, $b, $quotient, $remainder) = {
+    local $count
+    local $left
+    local $zero
+    local $one = 1
+    LDA $a
+    STO $left
+    LDA $zero
+    STO $count
+    LDA $b
+    BRZ done
+    loop LDA $left
+    SUB $b
+    BRP next
+    BR done
+    next STO $left
+    LDA $count
+    ADD $one
+    STO $count
+    BR loop
+    done LDA $left
+    STO $remainder
+    LDA $count
+    STO $quotient
+}
+
+# $quotient = $a / $b, rounding down
+macro DIVIDE($a, $b, $quotient) = {
+    local $unused
+    DIVMOD!($a, $b, $quotient, $unused)
+}
+
+# $result = $a mod $b
+macro MOD($a, $b, $result) = {
+    local $unused
+    DIVMOD!($a, $b, $unused, $result)
+    LDA $result
+}
+
+# $result = -$a
+macro NEGATE($a, $result) = {
+    local $zero
+    LDA $zero
+    SUB $a
+    STO $result
+}
+
+# $result = |$a|, where values from 500 upwards are negative
+macro ABS($a, $result) = {
+    local $half = 500
+    local $zero
+    LDA $a
+    SUB $half
+    BRP negative
+    LDA $a
+    BR done
+    negative LDA $zero
+    SUB $a
+    done STO $result
+}
+
+# branches to $less, $equal or $greater depending on how $a compares to $b, where values from 500 upwards are negative
+# adding 500 to each moves -500 to 0 and 499 to 999, so they can then be compared by subtracting
+macro COMPARE($a, $b, $less, $equal, $greater) = {
+    local $half = 500
+    local $biased
+    LDA $b
+    ADD $half
+    STO $biased
+    LDA $a
+    ADD $half
+    SUB $biased
+    BRZ $equal
+    BRP $greater
+    BR $less
+}
+
+# swaps the values of $a and $b
+macro SWAP($a, $b) = {
+    local $tmp
+    LDA $a
+    STO $tmp
+    LDA $b
+    STO $a
+    LDA $tmp
+    STO $b
+}
+
+# $result = the larger of $a and $b, where values from 500 upwards are negative
+macro MAX($a, $b, $result) = {
+    COMPARE!($a, $b, second, first, first)
+    second LDA $b
+    BR done
+    first LDA $a
+    done STO $result
+}
+
+# $result = the smaller of $a and $b, where values from 500 upwards are negative
+macro MIN($a, $b, $result) = {
+    COMPARE!($a, $b, first, first, second)
+    second LDA $b
+    BR done
+    first LDA $a
+    done STO $result
+}
@@ -0,0 +1,78 @@
+# std::wide - arithmetic on values from 0 to 999999, stored as a pair of mailboxes
+# A value is stored as its high part and low part, each from 0 to 999, so 123456 is 123 and 456.
+# Results wrap around past 999999, and negative values are stored in ten's complement, so -1 is 999 and 999.
+
+# $rh $rl = $ah $al + $bh $bl, carrying into the high part when the low part wraps around
+macro WIDE_ADD($ah, $al, $bh, $bl, $rh, $rl) = {
+    local $high
+    local $low
+    local $one = 1
+    LDA $ah
+    ADD $bh
+    STO $high
+    LDA $al
+    ADD $bl
+    STO $low
+    SUB $al
+    BRP done
+    LDA $high
+    ADD $one
+    STO $high
+    done LDA $high
+    STO $rh
+    LDA $low
+    STO $rl
+}
+
+# $rh $rl = $ah $al - $bh $bl, borrowing from the high part when the low part wraps around
+macro WIDE_SUB($ah, $al, $bh, $bl, $rh, $rl) = {
+    local $high
+    local $low
+    local $one = 1
+    LDA $ah
+    SUB $bh
+    STO $high
+    LDA $al
+    SUB $bl
+    BRP done
+    LDA $high
+    SUB $one
+    STO $high
+    done LDA $al
+    SUB $bl
+    STO $low
+    LDA $high
+    STO $rh
+    LDA $low
+    STO $rl
+}
+
+# branches to $less, $equal or $greater depending on how $ah $al compares to $bh $bl,
+# where values with a high part from 500 upwards are negative
+# adding 500 to each high part moves -500 to 0 and 499 to 999, so they can then be compared by subtracting
+macro WIDE_COMPARE($ah, $al, $bh, $bl, $less, $equal, $greater) = {
+    local $half = 500
+    local $biased
+    LDA $bh
+    ADD $half
+    STO $biased
+    LDA $ah
+    ADD $half
+    SUB $biased
+    BRZ low
+    BRP $greater
+    BR $less
+    low LDA $al
+    SUB $bl
+    BRZ $equal
+    BRP $greater
+    BR $less
+}
+
+# outputs $high followed by $low as two separate numbers without padding, so 1005 is output as 1 and then 5
+macro WIDE_OUT($high, $low) = {
+    LDA $high
+    OUT
+    LDA $low
+    OUT
+}
@@ -0,0 +1,102 @@
+use crate::{
+    diagnostics::{Diagnostic, Span},
+    parser::{directive::Section, instruction::Opcode, Item},
+};
+
+/// Lays out an expanded program so that data is never run as an instruction.
+/// Items in a `section data` block and every `DAT` outside one are moved after the rest of the program,
+/// keeping their labels and their order. Returns the new program along with a warning for each place
+/// the code could run on into the data.
+pub(crate) fn layout<'a>(program: &[Item<'a>]) -> (Vec<Item<'a>>, Vec<Diagnostic>) {
+    let mut code = Vec::new();
+    let mut data = Vec::new();
+
+    let mut section = Section::Code;
+    for item in program {
+        match item {
+            Item::Section(new, _) => section = *new,
+            Item::Instruction(instruction)
+                if section == Section::Data || instruction.get_opcode() == Some(&Opcode::DAT) =>
+            {
+                data.push(item.clone())
+            }
+            _ if section == Section::Data => data.push(item.clone()),
+            _ => code.push(item.clone()),
+        }
+    }
+
+    let warnings = fall_through(&code, &data).into_iter().collect();
+    code.extend(data);
+
+    (code, warnings)
+}
+
+/// Creates a warning if the last instruction of the code can be followed by running the first item of data,
+/// which happens unless it halts or branches away
+fn fall_through<'b>(code: &'b [Item], data: &'b [Item]) -> Option<Diagnostic> {
+    let instruction = |item: &'b Item| match item {
+        Item::Instruction(instruction) => Some(instruction),
+        _ => None,
+    };
+    let last = code.iter().rev().find_map(instruction)?;
+    let first = data.iter().find_map(instruction)?;
+
+    match last.get_opcode() {
+        Some(Opcode::HLT | Opcode::BR) => None,
+        _ => Some(
+            Diagnostic::warning(
+                "execution can run on from the end of the code into data",
+                last.get_span(),
+            )
+            .with_note("the first data is here", first.get_span())
+            .with_note(
+                "end the code with `HLT` or `BR` to stop this",
+                Span::default(),
+            ),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parse, Severity};
+
+    #[test]
+    fn test_layout() {
+        let program = parse(
+            "   LDA count
+            count DAT 3
+                section data
+            table DAT 1
+                DAT 2
+                section code
+                OUT
+                HLT",
+        )
+        .unwrap();
+
+        let (program, warnings) = layout(&program);
+        assert_eq!(
+            crate::render(&program, Default::default()),
+            "\tLDA\tcount\n\tOUT\n\tHLT\ncount\tDAT\t3\ntable\tDAT\t1\n\tDAT\t2\n"
+        );
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_fall_through() {
+        let program = parse("IN\nOUT\nvalue DAT 5").unwrap();
+
+        let (_, warnings) = layout(&program);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].get_severity(), Severity::Warning);
+        assert_eq!(
+            warnings[0].get_message(),
+            "execution can run on from the end of the code into data"
+        );
+
+        // nothing can run on if there is no data
+        assert!(layout(&parse("IN\nOUT").unwrap()).1.is_empty());
+    }
+}
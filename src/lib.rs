@@ -0,0 +1,120 @@
+//! A preprocessor for [LMC](https://mjrbordewich.webspace.durham.ac.uk/wp-content/uploads/sites/186/2021/04/LMC-Instruction-Set.pdf)
+//! assembly, adding macro support on top of the standard instruction set.
+//!
+//! Preprocessing is split into separate stages, each of which can be called on its own:
+//! * [`parse`] turns source text into a list of [`Item`]s, optionally restricted to a single [`Dialect`],
+//!   while [`load`] does the same for a file in a [`SourceMap`] along with every file it includes
+//! * [`expand`] replaces every macro call with the body of its declaration
+//! * [`resolve_expressions`] replaces operands such as `table+2` with their value, once labels have addresses
+//! * [`render`] turns the expanded program back into LMC assembly
+//! * [`assemble`] turns the expanded program into a [`MemoryImage`] of machine code
+//!
+//! Assembled programs can then be run using a simulated LMC, the [`Machine`].
+//!
+//! Any errors are reported as [`Diagnostic`]s, which can be traced back to a file, line and column using a
+//! [`SourceMap`] containing the original source text.
+//!
+//! ```
+//! let program = lmc_preprocessor::parse("macro OUT_TWICE() = {\n OUT\n OUT\n}\nOUT_TWICE!()").unwrap();
+//! let expanded = lmc_preprocessor::expand(&program, &Default::default()).unwrap();
+//!
+//! assert_eq!(lmc_preprocessor::render(&expanded, Default::default()), "\tOUT\n\tOUT\n");
+//! ```
+
+mod assembler;
+mod diagnostics;
+mod include;
+mod layout;
+mod parser;
+mod preprocessor;
+mod simulator;
+
+pub use assembler::{MemoryImage, MAILBOXES};
+pub use diagnostics::{Diagnostic, Location, Severity, SourceMap, Span};
+pub use include::IncludeOptions;
+pub use parser::{
+    dialect::Dialect,
+    directive::{Condition, Section},
+    for_each::ForEach,
+    instruction::{Instruction, Mnemonics, Opcode, Operation},
+    macros::{macro_call::MacroCall, macro_declaration::MacroDeclaration},
+    repeat::Repeat,
+    Item,
+};
+pub use preprocessor::ExpandOptions;
+pub use simulator::{Machine, Output, RuntimeError};
+
+/// Parses an entire program into a list of items, returning diagnostics if the program could not be parsed.
+/// Every spelling of every opcode is accepted, see [`parse_with`] to only accept a single dialect.
+pub fn parse(input: &str) -> Result<Vec<Item<'_>>, Vec<Diagnostic>> {
+    parse_with(input, Dialect::default())
+}
+
+/// Parses an entire program written in the given dialect, returning diagnostics if the program could not be parsed
+/// or breaks one of the dialect's rules.
+pub fn parse_with(input: &str, dialect: Dialect) -> Result<Vec<Item<'_>>, Vec<Diagnostic>> {
+    let program = parser::parse(input, dialect).map_err(|diagnostic| vec![diagnostic])?;
+    validate(program, dialect)
+}
+
+/// Parses the source with the given id like [`parse_with`], replacing each `include "file"` with the items in that file.
+/// Included files are found relative to the file including them, then in each of the search paths,
+/// and are added to the source map so diagnostics can point into them.
+pub fn load<'a>(
+    sources: &'a mut SourceMap,
+    id: usize,
+    dialect: Dialect,
+    options: &IncludeOptions,
+) -> Result<Vec<Item<'a>>, Vec<Diagnostic>> {
+    validate(include::load(sources, id, dialect, options)?, dialect)
+}
+
+/// Checks a parsed program follows the rules of its dialect
+fn validate(program: Vec<Item>, dialect: Dialect) -> Result<Vec<Item>, Vec<Diagnostic>> {
+    let errors = parser::validate(&program, dialect);
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Expands a parsed program, replacing all macro calls with the body of the matching declaration.
+/// Macro declarations are removed from the output.
+pub fn expand<'a>(
+    program: &[Item<'a>],
+    options: &ExpandOptions,
+) -> Result<Vec<Item<'a>>, Vec<Diagnostic>> {
+    preprocessor::replace_macro(program, options)
+}
+
+/// Lays out an expanded program so data is never run as an instruction, moving the contents of `section data` blocks
+/// and every other `DAT` after the code. Returns the new program along with warnings for code which can run on into the data.
+pub fn layout<'a>(program: &[Item<'a>]) -> (Vec<Item<'a>>, Vec<Diagnostic>) {
+    layout::layout(program)
+}
+
+/// Renders a program as LMC assembly, one item per line, written the way the given dialect writes it.
+pub fn render(program: &[Item], dialect: Dialect) -> String {
+    program.iter().fold(String::new(), |acc, item| {
+        acc + &item.to_string_with(dialect) + "\n"
+    })
+}
+
+/// Replaces every operand of an expanded program which is an expression, such as `table+2`, with its value,
+/// reporting any which are out of range. Labels are given the address they would have when assembled.
+pub fn resolve_expressions<'a>(program: &[Item<'a>]) -> Result<Vec<Item<'a>>, Vec<Diagnostic>> {
+    assembler::resolve_expressions(program)
+}
+
+/// Assembles an expanded program into machine code, resolving labels to the address of the mailbox they label.
+pub fn assemble(program: &[Item]) -> Result<MemoryImage, Vec<Diagnostic>> {
+    assembler::assemble(program)
+}
+
+/// Main preprocessing function - parses, expands and renders the given program.
+pub fn preprocess(input: &str, options: &ExpandOptions) -> Result<String, Vec<Diagnostic>> {
+    let program = parse(input)?;
+    let program = resolve_expressions(&expand(&program, options)?)?;
+    Ok(render(&program, Dialect::default()))
+}
@@ -1,32 +1,141 @@
-use clap::Parser;
-use parser::{parse_program, Item};
-use preprocessor::replace_macro;
+use clap::{ArgEnum, Args, Parser, Subcommand};
+use lmc_preprocessor::{
+    assemble, expand, layout, load, render, resolve_expressions, Diagnostic, Dialect,
+    ExpandOptions, IncludeOptions, Item, Machine, Mnemonics, Output, SourceMap,
+};
 use std::{
     fs::File,
-    io::{BufWriter, Read, Write},
+    io::{BufRead, BufWriter, Read, Write},
+    path::PathBuf,
 };
 
-mod parser;
-mod preprocessor;
-
-/// Main preprocessing function - currently just parses and replaces macro calls with declarations.
-fn preprocess(input: &str) -> Option<Vec<Item>> {
-    let program = parse_program(input).ok()?.1;
-    Some(replace_macro(&program))
-}
-
 #[derive(Parser)]
 #[clap(version = "0.1")]
 struct Options {
     path: Option<String>,
     #[clap(short, long)]
     out_file: Option<String>,
+    /// Replace calls to undefined macros with nothing instead of failing
+    #[clap(long, global = true)]
+    allow_undefined_macros: bool,
+    /// Maximum number of macro calls that can be nested inside each other
+    #[clap(long, global = true, default_value = "64")]
+    max_expansion_depth: usize,
+    /// Maximum number of items the expanded program can have, including those made by repeat and for blocks
+    #[clap(long, global = true, default_value = "100000")]
+    max_expanded_items: usize,
+    /// Define a symbol for `if` and `ifdef`, such as "-D DEBUG" or "-D LEVEL=2"
+    #[clap(
+        short = 'D',
+        long = "define",
+        global = true,
+        multiple_occurrences(true),
+        multiple_values(false)
+    )]
+    defines: Vec<String>,
+    /// Directories to search for included files, after the directory of the file including them
+    #[clap(
+        short = 'I',
+        long = "include-path",
+        global = true,
+        multiple_occurrences(true),
+        multiple_values(false)
+    )]
+    include_paths: Vec<PathBuf>,
+    /// Move data after the code, including `section data` blocks, warning if code can run on into it
+    #[clap(long, global = true)]
+    layout: bool,
+    /// What to output: the expanded assembly, or the assembled machine code as a memory image or a list of numbers
+    #[clap(long, arg_enum, default_value = "assembly")]
+    emit: Emit,
+    /// Which instruction set the program is written in, which decides the opcodes and comments it can use
+    #[clap(long, arg_enum, global = true, default_value = "any")]
+    dialect: DialectOption,
+    /// Which spelling to use for opcodes with more than one name, such as STO or STA, instead of the dialect's own
+    #[clap(long, arg_enum)]
+    mnemonics: Option<MnemonicsOption>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+/// The different forms the preprocessed program can be output in
+#[derive(ArgEnum, Clone, Copy)]
+enum Emit {
+    Assembly,
+    Image,
+    List,
+}
+
+/// The instruction sets a program can be written in
+#[derive(ArgEnum, Clone, Copy)]
+enum DialectOption {
+    Any,
+    Durham,
+    Higginson,
+    Madnick,
+}
+
+impl From<DialectOption> for Dialect {
+    fn from(option: DialectOption) -> Self {
+        match option {
+            DialectOption::Any => Dialect::any(),
+            DialectOption::Durham => Dialect::durham(),
+            DialectOption::Higginson => Dialect::higginson(),
+            DialectOption::Madnick => Dialect::madnick(),
+        }
+    }
+}
+
+/// The spellings opcodes can be written with, such as STO/IN/BR or STA/INP/BRA
+#[derive(ArgEnum, Clone, Copy)]
+enum MnemonicsOption {
+    Durham,
+    Common,
+    Madnick,
+}
+
+impl From<MnemonicsOption> for Mnemonics {
+    fn from(option: MnemonicsOption) -> Self {
+        match option {
+            MnemonicsOption::Durham => Mnemonics::Durham,
+            MnemonicsOption::Common => Mnemonics::Common,
+            MnemonicsOption::Madnick => Mnemonics::Madnick,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Assembles the program and runs it on a simulated LMC, printing each value it outputs
+    Run(RunOptions),
+}
+
+#[derive(Args)]
+struct RunOptions {
+    path: Option<String>,
+    /// Values given to IN instructions, such as "-i 20,5", read from stdin if none are given
+    #[clap(
+        short,
+        long,
+        multiple_occurrences(true),
+        multiple_values(false),
+        use_delimiter(true)
+    )]
+    input: Vec<u16>,
+    /// Maximum number of instructions to run before giving up
+    #[clap(long, default_value = "10000")]
+    max_steps: usize,
 }
 
 fn main() {
     let options = Options::parse();
 
-    let data = match options.path {
+    let path = match &options.command {
+        Some(Command::Run(run_options)) => &run_options.path,
+        None => &options.path,
+    };
+
+    let data = match path {
         Some(ref path) => std::fs::read_to_string(path).ok(),
         _ => {
             if atty::isnt(atty::Stream::Stdin) {
@@ -37,12 +146,111 @@ fn main() {
         }
     };
 
-    match data {
-        Some(data) => match preprocess(&data) {
-            Some(program) => output(&options, &program).unwrap_or_else(|err| println!("{}", err)),
-            None => println!("Failed to parse program!"),
-        },
-        None => println!("Failed to get input!"),
+    let data = match data {
+        Some(data) => data,
+        None => return println!("Failed to get input!"),
+    };
+
+    let mut sources = SourceMap::new();
+    let name = path.as_deref().unwrap_or("<stdin>");
+    let id = sources.add(name, data);
+
+    let mut dialect = Dialect::from(options.dialect);
+    if let Some(mnemonics) = options.mnemonics {
+        dialect = dialect.with_mnemonics(mnemonics.into());
+    }
+
+    let expand_options = ExpandOptions {
+        allow_undefined_macros: options.allow_undefined_macros,
+        max_expansion_depth: options.max_expansion_depth,
+        max_expanded_items: options.max_expanded_items,
+        // symbols without a value are defined as 1, the same as `define NAME`
+        symbols: options
+            .defines
+            .iter()
+            .map(|define| match define.split_once('=') {
+                Some((name, value)) => (name.to_string(), value.to_string()),
+                None => (define.to_string(), "1".to_string()),
+            })
+            .collect(),
+        dialect,
+    };
+
+    let include_options = IncludeOptions {
+        search_paths: options.include_paths.clone(),
+    };
+
+    // warnings can only be shown once the program no longer borrows the sources
+    let mut warnings = Vec::new();
+    let result = load(&mut sources, id, dialect, &include_options).and_then(|program| {
+        let program = expand(&program, &expand_options)?;
+        let program = if options.layout {
+            let (program, layout_warnings) = layout(&program);
+            warnings = layout_warnings;
+            program
+        } else {
+            program
+        };
+
+        match &options.command {
+            Some(Command::Run(run_options)) => run(&program, run_options),
+            None => preprocess(&program, dialect, options.emit).map(|program| {
+                output(&options, &program).unwrap_or_else(|err| println!("{}", err))
+            }),
+        }
+    });
+
+    for warning in warnings {
+        eprintln!("{}", warning.render(&sources));
+    }
+
+    if let Err(diagnostics) = result {
+        for diagnostic in diagnostics {
+            eprintln!("{}", diagnostic.render(&sources));
+        }
+        std::process::exit(1);
+    }
+}
+
+/// Assembles and runs the expanded program, printing each value it outputs
+fn run(program: &[Item], run_options: &RunOptions) -> Result<(), Vec<Diagnostic>> {
+    let image = assemble(program)?;
+
+    // if no input was given, read values from stdin as the program needs them
+    // this only works if the program itself was read from a file
+    let values: Box<dyn Iterator<Item = u16>> = if run_options.input.is_empty() {
+        Box::new(
+            std::io::stdin()
+                .lock()
+                .lines()
+                .map_while(Result::ok)
+                .flat_map(|line| line.split_whitespace().map(str::parse).collect::<Vec<_>>())
+                .map_while(Result::ok),
+        )
+    } else {
+        Box::new(run_options.input.clone().into_iter())
+    };
+
+    // numbers are written on their own line, while characters are written as they are
+    let result = Machine::new(&image).run(values, run_options.max_steps, |output| match output {
+        Output::Number(value) => println!("{}", value),
+        Output::Character(character) => print!("{}", character),
+    });
+
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Outputs the expanded program in the requested form
+fn preprocess(program: &[Item], dialect: Dialect, emit: Emit) -> Result<String, Vec<Diagnostic>> {
+    match emit {
+        Emit::Assembly => resolve_expressions(program).map(|program| render(&program, dialect)),
+        Emit::Image => assemble(program).map(|image| image.to_string()),
+        Emit::List => assemble(program).map(|image| image.to_list() + "\n"),
     }
 }
 
@@ -55,24 +263,15 @@ fn handle_stdin() -> Option<String> {
 }
 
 /// Outputs the program using the options provided
-fn output(options: &Options, program: &[Item]) -> Result<(), &'static str> {
+fn output(options: &Options, program: &str) -> Result<(), &'static str> {
     match &options.out_file {
         Some(path) => {
             let file = File::create(path).map_err(|_| "Failed to create file!")?;
             let mut writer = BufWriter::new(file);
 
-            for item in program {
-                writeln!(writer, "{}", item).map_err(|_| "Failed to write to file!")?;
-            }
-        }
-        None => {
-            println!(
-                "{}",
-                program
-                    .iter()
-                    .fold(String::new(), |acc, item| acc + "\n" + &item.to_string())
-            )
+            write!(writer, "{}", program).map_err(|_| "Failed to write to file!")?;
         }
+        None => print!("{}", program),
     }
 
     Ok(())
@@ -0,0 +1,117 @@
+use std::str::FromStr;
+
+use super::instruction::{Mnemonics, Opcode};
+
+/// A profile describing one of the LMC instruction sets in use, which disagree on the names of opcodes,
+/// which opcodes exist, how comments are written and what labels can be called
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Dialect {
+    name: &'static str,
+    opcodes: &'static [&'static str],
+    mnemonics: Mnemonics,
+    comments: &'static [&'static str],
+    opcode_labels: bool,
+}
+
+impl Dialect {
+    /// Accepts the opcodes and comments of every other dialect, writing opcodes with the Durham spelling
+    pub fn any() -> Self {
+        Self {
+            name: "any",
+            opcodes: Opcode::MNEMONICS,
+            mnemonics: Mnemonics::Durham,
+            comments: &["#", "//"],
+            opcode_labels: true,
+        }
+    }
+
+    /// The instruction set from the Durham LMC sheet
+    pub fn durham() -> Self {
+        Self {
+            name: "Durham",
+            opcodes: &[
+                "ADD", "SUB", "STO", "LDA", "BRZ", "BRP", "BR", "IN", "OUT", "HLT", "DAT",
+            ],
+            mnemonics: Mnemonics::Durham,
+            comments: &["#"],
+            opcode_labels: true,
+        }
+    }
+
+    /// The instruction set from Peter Higginson's LMC simulator, which adds OTC and uses `//` comments
+    pub fn higginson() -> Self {
+        Self {
+            name: "Higginson",
+            opcodes: &[
+                "ADD", "SUB", "STA", "LDA", "BRZ", "BRP", "BRA", "INP", "OUT", "OTC", "HLT", "COB",
+                "DAT",
+            ],
+            mnemonics: Mnemonics::Common,
+            comments: &["//"],
+            opcode_labels: false,
+        }
+    }
+
+    /// The instruction set from Stuart Madnick's original LMC, which halts with COB
+    pub fn madnick() -> Self {
+        Self {
+            name: "Madnick",
+            opcodes: &[
+                "ADD", "SUB", "STO", "LDA", "BRZ", "BRP", "BR", "IN", "OUT", "COB", "DAT",
+            ],
+            mnemonics: Mnemonics::Madnick,
+            comments: &["#"],
+            opcode_labels: false,
+        }
+    }
+
+    /// Creates a dialect identical to the current one, but writing opcodes with a different spelling
+    pub fn with_mnemonics(self, mnemonics: Mnemonics) -> Self {
+        Self { mnemonics, ..self }
+    }
+
+    /// Gets the name of the dialect
+    pub fn get_name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Gets every spelling of an opcode that the dialect accepts
+    pub fn get_opcodes(&self) -> &'static [&'static str] {
+        self.opcodes
+    }
+
+    /// Gets the spelling used when writing opcodes
+    pub fn get_mnemonics(&self) -> Mnemonics {
+        self.mnemonics
+    }
+
+    /// Gets every marker that can start a comment, the first of which is used when writing comments
+    pub fn get_comments(&self) -> &'static [&'static str] {
+        self.comments
+    }
+
+    /// Returns true if labels can have the same name as an opcode, such as "out DAT"
+    pub fn allows_opcode_labels(&self) -> bool {
+        self.opcode_labels
+    }
+
+    /// Returns true if the dialect accepts the given spelling of an opcode, ignoring case
+    pub fn accepts_opcode(&self, opcode: &str) -> bool {
+        self.opcodes
+            .iter()
+            .any(|accepted| accepted.eq_ignore_ascii_case(opcode))
+    }
+
+    /// Returns true if the dialect has the given opcode, under any of its spellings
+    pub fn has_opcode(&self, opcode: &Opcode) -> bool {
+        self.opcodes
+            .iter()
+            .any(|accepted| Opcode::from_str(accepted).is_ok_and(|accepted| accepted == *opcode))
+    }
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Self::any()
+    }
+}
@@ -0,0 +1,215 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_while1},
+    character::complete::{satisfy, space0, space1},
+    combinator::{consumed, map, not, opt, peek, recognize},
+    multi::separated_list1,
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    IResult,
+};
+
+use std::fmt::{self, Display, Formatter};
+
+use super::{identifier, Item};
+use crate::diagnostics::Span;
+
+/// The condition tested by an `if` directive
+#[derive(PartialEq, Debug, Clone)]
+pub enum Condition<'a> {
+    /// `ifdef NAME`, true if the symbol has been defined
+    Defined(&'a str),
+    /// `ifndef NAME`, true if the symbol hasn't been defined
+    NotDefined(&'a str),
+    /// `if NAME`, true if the symbol has been defined as anything other than 0
+    Set(&'a str),
+    /// `if NAME == value`
+    Equals(&'a str, &'a str),
+    /// `if NAME != value`
+    NotEquals(&'a str, &'a str),
+}
+
+/// The section of the program a `section` directive starts, which decides where the items after it are placed
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Section {
+    /// `section code`, for instructions which are run
+    Code,
+    /// `section data`, for storage which is placed after all of the code
+    Data,
+}
+
+impl Display for Section {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Section::Code => write!(f, "section code"),
+            Section::Data => write!(f, "section data"),
+        }
+    }
+}
+
+/// Matches a keyword, as long as it isn't just the start of a longer name such as "define_2"
+fn keyword<'a>(word: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(
+        tag(word),
+        peek(not(satisfy(|c: char| c.is_alphanumeric() || c == '_'))),
+    )
+}
+
+/// Matches the value of a symbol, such as "2" or "fast"
+fn value(input: &str) -> IResult<&str, &str> {
+    take_while1(|c: char| c.is_alphanumeric() || c == '_')(input)
+}
+
+/// Matches the condition of an `if`, `ifdef` or `ifndef` directive
+fn condition(input: &str) -> IResult<&str, Condition<'_>> {
+    let comparison = |operator| tuple((space0, tag(operator), space0));
+
+    alt((
+        map(
+            preceded(pair(keyword("ifdef"), space1), identifier),
+            Condition::Defined,
+        ),
+        map(
+            preceded(pair(keyword("ifndef"), space1), identifier),
+            Condition::NotDefined,
+        ),
+        preceded(
+            pair(keyword("if"), space1),
+            alt((
+                map(
+                    separated_pair(identifier, comparison("=="), value),
+                    |(name, value)| Condition::Equals(name, value),
+                ),
+                map(
+                    separated_pair(identifier, comparison("!="), value),
+                    |(name, value)| Condition::NotEquals(name, value),
+                ),
+                map(identifier, Condition::Set),
+            )),
+        ),
+    ))(input)
+}
+
+/// Matches the path of an included file, such as "\"lib/math.asm\""
+fn path(input: &str) -> IResult<&str, &str> {
+    delimited(
+        tag("\""),
+        take_while1(|c: char| c != '"' && c != '\n'),
+        tag("\""),
+    )(input)
+}
+
+/// Matches the name of a module of the standard library, such as "std::math"
+fn module(input: &str) -> IResult<&str, &str> {
+    recognize(separated_list1(tag("::"), identifier))(input)
+}
+
+/// Matches a directive, such as "include \"macros.asm\"", "use std::math", "const LIMIT = 25", "define DEBUG", "ifdef DEBUG",
+/// "else", "endif" or "section data"
+pub(crate) fn directive(input: &str) -> IResult<&str, Item<'_>> {
+    alt((
+        map(
+            consumed(preceded(
+                pair(keyword("const"), space1),
+                separated_pair(
+                    identifier,
+                    tuple((space0, tag("="), space0)),
+                    take_while1(|c: char| c.is_ascii_digit()),
+                ),
+            )),
+            |(fragment, (name, value))| Item::Constant(name, value, Span::of(fragment)),
+        ),
+        map(
+            consumed(preceded(pair(keyword("include"), space1), path)),
+            |(fragment, path)| Item::Include(path, Span::of(fragment)),
+        ),
+        map(
+            consumed(preceded(pair(keyword("use"), space1), module)),
+            |(fragment, module)| Item::Use(module, Span::of(fragment)),
+        ),
+        map(
+            consumed(preceded(
+                pair(keyword("define"), space1),
+                pair(
+                    identifier,
+                    opt(preceded(tuple((space0, tag("="), space0)), value)),
+                ),
+            )),
+            |(fragment, (name, value))| Item::Define(name, value, Span::of(fragment)),
+        ),
+        map(consumed(condition), |(fragment, condition)| {
+            Item::If(condition, Span::of(fragment))
+        }),
+        map(keyword("else"), |fragment| Item::Else(Span::of(fragment))),
+        map(keyword("endif"), |fragment| Item::EndIf(Span::of(fragment))),
+        map(
+            consumed(preceded(
+                pair(keyword("section"), space1),
+                alt((
+                    map(keyword("code"), |_| Section::Code),
+                    map(keyword("data"), |_| Section::Data),
+                )),
+            )),
+            |(fragment, section)| Item::Section(section, Span::of(fragment)),
+        ),
+    ))(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_directive_parser() {
+        macro_rules! test_directive {
+            ($($input:expr => $item:expr),*) => {
+                $(
+                    assert_eq!(directive($input), Ok(("", $item)));
+                )*
+            };
+        }
+
+        test_directive!(
+            "include \"lib/math.asm\"" => Item::Include("lib/math.asm", Span::default()),
+            "use std::math" => Item::Use("std::math", Span::default()),
+            "const LIMIT = 25" => Item::Constant("LIMIT", "25", Span::default()),
+            "define DEBUG" => Item::Define("DEBUG", None, Span::default()),
+            "define LEVEL = 2" => Item::Define("LEVEL", Some("2"), Span::default()),
+            "ifdef DEBUG" => Item::If(Condition::Defined("DEBUG"), Span::default()),
+            "ifndef DEBUG" => Item::If(Condition::NotDefined("DEBUG"), Span::default()),
+            "if DEBUG" => Item::If(Condition::Set("DEBUG"), Span::default()),
+            "if LEVEL == 2" => Item::If(Condition::Equals("LEVEL", "2"), Span::default()),
+            "if MODE!=fast" => Item::If(Condition::NotEquals("MODE", "fast"), Span::default()),
+            "section data" => Item::Section(Section::Data, Span::default()),
+            "else" => Item::Else(Span::default()),
+            "endif" => Item::EndIf(Span::default())
+        );
+
+        assert!(directive("endif_2 DAT").is_err());
+        assert!(directive("defined DAT").is_err());
+        assert!(directive("const LIMIT = ten").is_err());
+    }
+
+    #[test]
+    fn test_keyword_labels() {
+        use crate::parser::{
+            dialect::Dialect,
+            instruction::{Instruction, Opcode},
+            parse,
+        };
+
+        // a keyword followed by an opcode is a labelled instruction, as it was before directives existed
+        for keyword in [
+            "const", "include", "use", "define", "if", "ifdef", "ifndef", "else", "endif",
+            "section",
+        ] {
+            assert_eq!(
+                parse(&format!("{keyword} OUT"), Dialect::default()),
+                Ok(vec![Item::Instruction(Instruction::new(
+                    Some(keyword),
+                    Opcode::OUT,
+                    None
+                ))])
+            );
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use std::{borrow::Cow, iter::Peekable, str::CharIndices};
+
+use nom::{error::ErrorKind, IResult};
+
+/// Returns true if the character can be part of a name in an expression, such as a label or macro parameter
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '$'
+}
+
+/// Returns true if the character is an operator or bracket in an expression
+fn is_operator_char(c: char) -> bool {
+    ['+', '-', '*', '/', '(', ')'].contains(&c)
+}
+
+/// Matches an operand, which is either a single name or number such as "count",
+/// or an arithmetic expression such as "table+3" or "LIMIT*2-1".
+/// A `//` comment straight after the operand isn't treated as division.
+pub(crate) fn operand(input: &str) -> IResult<&str, &str> {
+    let end = input
+        .char_indices()
+        .find(|&(index, c)| {
+            !(is_name_char(c) || is_operator_char(c)) || input[index..].starts_with("//")
+        })
+        .map_or(input.len(), |(index, _)| index);
+
+    Ok((&input[end..], &input[..end]))
+}
+
+/// Matches an operand which can be given as the argument to a macro call,
+/// which can't contain brackets as they would be confused with the end of the call
+pub(crate) fn argument(input: &str) -> IResult<&str, &str> {
+    let end = input
+        .find(|c: char| !(is_name_char(c) || ['+', '-', '*', '/'].contains(&c)))
+        .unwrap_or(input.len());
+
+    if end == 0 {
+        Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            ErrorKind::TakeWhile1,
+        )))
+    } else {
+        Ok((&input[end..], &input[..end]))
+    }
+}
+
+/// Returns true if the operand is an expression rather than a single name or number
+pub(crate) fn is_expression(operand: &str) -> bool {
+    operand.contains(is_operator_char)
+}
+
+/// Replaces each name in the operand for which the function returns a replacement, keeping the operators between them.
+/// Replacements which are expressions themselves are bracketed, so "$a*2" with "x+1" for "$a" becomes "(x+1)*2".
+pub(crate) fn substitute_names<'a, 'b>(
+    operand: &'b str,
+    mut replacement: impl FnMut(&str) -> Option<Cow<'a, str>>,
+) -> Option<Cow<'a, str>> {
+    // the common case of a single name can be replaced without copying
+    if !is_expression(operand) {
+        return replacement(operand);
+    }
+
+    let mut changed = false;
+    let mut output = String::with_capacity(operand.len());
+    let mut rest = operand;
+
+    while let Some(c) = rest.chars().next() {
+        let length = if is_name_char(c) {
+            rest.find(|c| !is_name_char(c)).unwrap_or(rest.len())
+        } else {
+            c.len_utf8()
+        };
+        let (token, remaining) = rest.split_at(length);
+
+        match replacement(token).filter(|_| is_name_char(c)) {
+            Some(new) if is_expression(&new) => {
+                output.push('(');
+                output.push_str(&new);
+                output.push(')');
+                changed = true;
+            }
+            Some(new) => {
+                output.push_str(&new);
+                changed = true;
+            }
+            None => output.push_str(token),
+        }
+
+        rest = remaining;
+    }
+
+    changed.then_some(Cow::Owned(output))
+}
+
+/// Evaluates an operand, using the function to find the value of each name or explain why it has none.
+/// Returns a message describing the problem if the operand can't be evaluated.
+pub(crate) fn evaluate(
+    operand: &str,
+    lookup: impl Fn(&str) -> Result<i64, String>,
+) -> Result<i64, String> {
+    let mut evaluator = Evaluator {
+        operand,
+        chars: operand.char_indices().peekable(),
+        lookup,
+    };
+
+    let value = evaluator.expression()?;
+    match evaluator.chars.next() {
+        Some((_, c)) => Err(format!("unexpected `{}` in operand `{}`", c, operand)),
+        None => Ok(value),
+    }
+}
+
+/// A recursive descent evaluator for operand expressions, where `*` and `/` bind tighter than `+` and `-`
+struct Evaluator<'o, F> {
+    operand: &'o str,
+    chars: Peekable<CharIndices<'o>>,
+    lookup: F,
+}
+
+impl<'o, F: Fn(&str) -> Result<i64, String>> Evaluator<'o, F> {
+    /// Evaluates a sum or difference of terms, such as "a+b*2-1"
+    fn expression(&mut self) -> Result<i64, String> {
+        let mut value = self.term()?;
+
+        while let Some(&(_, operator)) = self.chars.peek() {
+            match operator {
+                '+' | '-' => {
+                    self.chars.next();
+                    let term = self.term()?;
+                    value = if operator == '+' {
+                        value.saturating_add(term)
+                    } else {
+                        value.saturating_sub(term)
+                    };
+                }
+                _ => break,
+            }
+        }
+
+        Ok(value)
+    }
+
+    /// Evaluates a product or quotient of factors, such as "b*2"
+    fn term(&mut self) -> Result<i64, String> {
+        let mut value = self.factor()?;
+
+        while let Some(&(_, operator)) = self.chars.peek() {
+            match operator {
+                '*' => {
+                    self.chars.next();
+                    value = value.saturating_mul(self.factor()?);
+                }
+                '/' => {
+                    self.chars.next();
+                    let divisor = self.factor()?;
+                    if divisor == 0 {
+                        return Err(format!("division by zero in operand `{}`", self.operand));
+                    }
+                    value = value.saturating_div(divisor);
+                }
+                _ => break,
+            }
+        }
+
+        Ok(value)
+    }
+
+    /// Evaluates a single number, name, negation or bracketed expression
+    fn factor(&mut self) -> Result<i64, String> {
+        match self.chars.next() {
+            Some((_, '-')) => Ok(self.factor()?.saturating_neg()),
+            Some((_, '(')) => {
+                let value = self.expression()?;
+                match self.chars.next() {
+                    Some((_, ')')) => Ok(value),
+                    _ => Err(format!("missing `)` in operand `{}`", self.operand)),
+                }
+            }
+            Some((start, c)) if is_name_char(c) => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(index, c)) = self.chars.peek() {
+                    if !is_name_char(c) {
+                        break;
+                    }
+                    end = index + c.len_utf8();
+                    self.chars.next();
+                }
+
+                let name = &self.operand[start..end];
+                if name.chars().all(|c| c.is_ascii_digit()) {
+                    Ok(name.parse().unwrap_or(i64::MAX))
+                } else {
+                    (self.lookup)(name)
+                }
+            }
+            Some((_, c)) => Err(format!("unexpected `{}` in operand `{}`", c, self.operand)),
+            None => Err(format!("incomplete operand `{}`", self.operand)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_operand_parser() {
+        assert_eq!(operand("table+3 # comment"), Ok((" # comment", "table+3")));
+        assert_eq!(operand("(a-b)*2"), Ok(("", "(a-b)*2")));
+        assert_eq!(operand("a//comment"), Ok(("//comment", "a")));
+        assert_eq!(argument("x+1, y)"), Ok((", y)", "x+1")));
+    }
+
+    #[test]
+    fn test_substitute_names() {
+        let replace = |name: &str| match name {
+            "$a" => Some(Cow::Borrowed("x+1")),
+            "loop" => Some(Cow::Borrowed("loop_1")),
+            _ => None,
+        };
+
+        assert_eq!(substitute_names("$a", replace), Some("x+1".into()));
+        assert_eq!(substitute_names("$a*2", replace), Some("(x+1)*2".into()));
+        assert_eq!(substitute_names("loop+1", replace), Some("loop_1+1".into()));
+        assert_eq!(substitute_names("end-start", replace), None);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let lookup = |name: &str| match name {
+            "table" => Ok(20),
+            "LIMIT" => Ok(25),
+            _ => Err(format!("undefined label `{}`", name)),
+        };
+
+        assert_eq!(evaluate("7", lookup), Ok(7));
+        assert_eq!(evaluate("table+3", lookup), Ok(23));
+        assert_eq!(evaluate("LIMIT*2-1", lookup), Ok(49));
+        assert_eq!(evaluate("(LIMIT-table)*-2", lookup), Ok(-10));
+        assert_eq!(evaluate("LIMIT/2", lookup), Ok(12));
+        assert_eq!(
+            evaluate("end-start", lookup),
+            Err("undefined label `end`".into())
+        );
+        assert_eq!(
+            evaluate("1/0", lookup),
+            Err("division by zero in operand `1/0`".into())
+        );
+        // values too large to store saturate instead of overflowing
+        assert_eq!(evaluate("-(-99999999999999999999-1)", lookup), Ok(i64::MAX));
+        assert_eq!(
+            evaluate("(-99999999999999999999-1)/-1", lookup),
+            Ok(i64::MAX)
+        );
+        assert_eq!(
+            evaluate("(1+2", lookup),
+            Err("missing `)` in operand `(1+2`".into())
+        );
+        assert_eq!(
+            evaluate("1+", lookup),
+            Err("incomplete operand `1+`".into())
+        );
+    }
+}
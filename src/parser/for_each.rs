@@ -0,0 +1,169 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use nom::{
+    bytes::complete::tag,
+    character::complete::{multispace0, space0, space1},
+    combinator::{consumed, cut, map, recognize},
+    multi::separated_list0,
+    sequence::{delimited, pair, preceded, tuple},
+    IResult,
+};
+
+use super::{
+    dialect::Dialect,
+    expression::argument,
+    identifier,
+    macros::macro_declaration::{local_labels, substitute_argument_item, Argument},
+    Item,
+};
+use crate::diagnostics::Span;
+
+/// Stores information about a block which is repeated once for each value in a list, such as "for $x in a, b { ADD $x }"
+#[derive(PartialEq, Debug, Clone)]
+pub struct ForEach<'a> {
+    parameter: &'a str,
+    values: Vec<Cow<'a, str>>,
+    body: Vec<Item<'a>>,
+    span: Span,
+}
+
+impl<'a> ForEach<'a> {
+    /// Creates a new for each block from the given information
+    pub fn new(parameter: &'a str, values: Vec<Cow<'a, str>>, body: Vec<Item<'a>>) -> Self {
+        Self {
+            parameter,
+            values,
+            body,
+            span: Span::default(),
+        }
+    }
+
+    /// Sets the span of source text the for each block was parsed from
+    pub(crate) fn with_span(self, span: Span) -> Self {
+        Self { span, ..self }
+    }
+
+    /// Creates a new for each block identical to the current one, but with different values and body
+    pub(crate) fn clone_with(&self, values: Vec<Cow<'a, str>>, body: Vec<Item<'a>>) -> Self {
+        Self {
+            parameter: self.parameter,
+            values,
+            body,
+            span: self.span,
+        }
+    }
+
+    /// Gets the parameter which is replaced with each value in turn
+    pub fn get_parameter(&self) -> &'a str {
+        self.parameter
+    }
+
+    /// Gets the values the body is repeated for
+    pub fn get_values(&self) -> &Vec<Cow<'a, str>> {
+        &self.values
+    }
+
+    /// Gets the repeated body
+    pub fn get_body(&self) -> &Vec<Item<'a>> {
+        &self.body
+    }
+
+    /// Gets the span of source text the for each block was parsed from
+    pub fn get_span(&self) -> Span {
+        self.span
+    }
+
+    /// Gets the labels defined in the body which are local to each repetition.
+    /// This is every label defined by an instruction in the body, other than parameters and labels declared `global`.
+    pub fn get_local_labels(&self) -> Vec<&str> {
+        local_labels(&self.body)
+    }
+
+    /// Creates a copy of the body for a single value, replacing the parameter with it
+    /// and renaming any labels in the given map.
+    pub(crate) fn substitute(
+        &self,
+        value: &Cow<'a, str>,
+        renames: &HashMap<&str, String>,
+    ) -> Vec<Item<'a>> {
+        let mut map: HashMap<_, _> = renames
+            .iter()
+            .map(|(&old, new)| (old, Argument::Value(Cow::Owned(new.clone()))))
+            .collect();
+        map.insert(self.parameter, Argument::Value(value.clone()));
+
+        self.body
+            .iter()
+            .map(|item| substitute_argument_item(item, &map))
+            .collect()
+    }
+}
+
+/// Matches a for each block, such as "for $x in a, b, c { ADD $x }" or "for $x in $rest { ADD $x }"
+pub(crate) fn for_each(input: &str, dialect: Dialect) -> IResult<&str, ForEach<'_>> {
+    map(
+        consumed(tuple((
+            // matches the parameter
+            preceded(
+                pair(tag("for"), space1),
+                recognize(pair(tag("$"), identifier)),
+            ),
+            // matches the list of values
+            preceded(
+                tuple((space1, tag("in"), space1)),
+                separated_list0(pair(tag(","), space0), argument),
+            ),
+            // matches the body, which must be closed once it has been opened
+            delimited(
+                pair(multispace0, tag("{")),
+                |input| super::parse_program(input, dialect),
+                cut(pair(multispace0, tag("}"))),
+            ),
+        ))),
+        |(fragment, (parameter, values, body))| {
+            let values = values.into_iter().map(Cow::Borrowed).collect();
+            ForEach::new(parameter, values, body).with_span(Span::of(fragment))
+        },
+    )(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::instruction::{Instruction, Opcode};
+
+    #[test]
+    fn test_for_each_parsing() {
+        let parsed = for_each(
+            "for $x in a, b+1 {
+                next ADD $x
+            }",
+            Dialect::default(),
+        )
+        .unwrap()
+        .1;
+
+        assert_eq!(
+            parsed,
+            ForEach::new(
+                "$x",
+                vec!["a".into(), "b+1".into()],
+                vec![Item::Instruction(Instruction::new(
+                    Some("next"),
+                    Opcode::ADD,
+                    Some("$x")
+                ))]
+            )
+        );
+
+        let renames = vec![("next", "next_1".to_string())].into_iter().collect();
+        assert_eq!(
+            parsed.substitute(&parsed.get_values()[1], &renames),
+            vec![Item::Instruction(Instruction::new(
+                Some("next_1"),
+                Opcode::ADD,
+                Some("b+1")
+            ))]
+        );
+    }
+}
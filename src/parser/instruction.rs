@@ -1,83 +1,231 @@
 use std::{
+    borrow::Cow,
     fmt::{self, Display, Formatter},
     str::FromStr,
 };
 
 use nom::{
     branch::alt,
-    bytes::complete::{tag_no_case, take_while},
+    bytes::complete::{tag, tag_no_case, take_while, take_while1},
     character::complete::{multispace1, space0, space1},
-    combinator::{eof, map, map_opt, opt, peek},
-    sequence::{preceded, terminated, tuple},
+    combinator::{consumed, eof, map, map_opt, opt, peek, recognize, verify},
+    sequence::{pair, preceded, terminated, tuple},
     AsChar, IResult,
 };
-use strum::{Display, EnumString, EnumVariantNames, VariantNames};
+use strum::{Display, EnumString, EnumVariantNames, IntoStaticStr};
+
+use super::{dialect::Dialect, expression::operand};
+use crate::diagnostics::Span;
 
 /// Stores information about a single instruction
 #[derive(PartialEq, Debug, Clone)]
-pub(crate) struct Instruction<'a> {
-    label: Option<&'a str>,
-    opcode: Opcode,
-    operand: Option<&'a str>,
+pub struct Instruction<'a> {
+    label: Option<Cow<'a, str>>,
+    operation: Operation<'a>,
+    operand: Option<Cow<'a, str>>,
+    /// The operand as it was written, if it has since had constants replaced with their values
+    written_operand: Option<Cow<'a, str>>,
+    span: Span,
 }
 
 impl<'a> Instruction<'a> {
     /// Creates a new instruction from the given information
-    pub(crate) fn new(label: Option<&'a str>, opcode: Opcode, operand: Option<&'a str>) -> Self {
+    pub fn new(
+        label: Option<&'a str>,
+        operation: impl Into<Operation<'a>>,
+        operand: Option<&'a str>,
+    ) -> Self {
         Self {
-            label,
-            opcode,
-            operand,
+            label: label.map(Cow::Borrowed),
+            operation: operation.into(),
+            operand: operand.map(Cow::Borrowed),
+            written_operand: None,
+            span: Span::default(),
         }
     }
 
+    /// Sets the span of source text the instruction was parsed from
+    pub(crate) fn with_span(self, span: Span) -> Self {
+        Self { span, ..self }
+    }
+
     /// Creates a new instruction identical to the current one, but with a different operand
-    pub(crate) fn clone_with_operand(&self, operand: &'a str) -> Self {
+    pub(crate) fn clone_with_operand(&self, operand: Cow<'a, str>) -> Self {
+        Self {
+            operand: Some(operand),
+            ..self.clone()
+        }
+    }
+
+    /// Creates a new instruction identical to the current one, but with its operand replaced by the same value
+    /// written differently, such as with constants replaced, so errors can still show the operand as it was written
+    pub(crate) fn clone_with_resolved_operand(&self, operand: Cow<'a, str>) -> Self {
         Self {
-            label: self.label,
-            opcode: self.opcode.clone(),
+            written_operand: self
+                .written_operand
+                .clone()
+                .or_else(|| self.operand.clone()),
             operand: Some(operand),
+            ..self.clone()
         }
     }
 
+    /// Creates a new instruction identical to the current one, but with a different label
+    pub(crate) fn clone_with_label(&self, label: Cow<'a, str>) -> Self {
+        Self {
+            label: Some(label),
+            ..self.clone()
+        }
+    }
+
+    /// Gets the instructions label
+    pub fn get_label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Creates a new instruction identical to the current one, but with a different operation
+    pub(crate) fn clone_with_operation(&self, operation: Operation<'a>) -> Self {
+        Self {
+            operation,
+            ..self.clone()
+        }
+    }
+
+    /// Gets the instructions opcode, or None if it is still a macro parameter
+    pub fn get_opcode(&self) -> Option<&Opcode> {
+        match &self.operation {
+            Operation::Opcode(opcode) => Some(opcode),
+            Operation::Parameter(_) => None,
+        }
+    }
+
+    /// Gets the instructions operation
+    pub fn get_operation(&self) -> &Operation<'a> {
+        &self.operation
+    }
+
     /// Gets the instructions operand
-    pub(crate) fn get_operand(&self) -> Option<&'a str> {
-        self.operand
+    pub fn get_operand(&self) -> Option<&str> {
+        self.operand.as_deref()
+    }
+
+    /// Gets the instructions operand as it was written, before any constants in it were replaced with their values
+    pub(crate) fn get_written_operand(&self) -> Option<&str> {
+        self.written_operand.as_deref().or(self.get_operand())
+    }
+
+    /// Gets the span of source text the instruction was parsed from
+    pub fn get_span(&self) -> Span {
+        self.span
+    }
+}
+
+impl<'a> Instruction<'a> {
+    /// Formats the instruction, spelling its opcode the way the given dialect does
+    pub fn to_string_with(&self, dialect: Dialect) -> String {
+        let operation = match &self.operation {
+            Operation::Opcode(opcode) => opcode.mnemonic(dialect.get_mnemonics()),
+            Operation::Parameter(parameter) => parameter,
+        };
+
+        match (&self.label, &self.operand) {
+            (Some(label), Some(operand)) => format!("{}\t{}\t{}", label, operation, operand),
+            (Some(label), None) => format!("{}\t{}", label, operation),
+            (None, Some(operand)) => format!("\t{}\t{}", operation, operand),
+            _ => format!("\t{}", operation),
+        }
     }
 }
 
 impl<'a> Display for Instruction<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match (self.label, self.operand) {
-            (Some(label), Some(operand)) => {
-                write!(f, "{}\t{}\t{}", label, self.opcode.to_string(), operand)
-            }
-            (Some(label), None) => write!(f, "{}\t{}", label, self.opcode.to_string()),
-            (None, Some(operand)) => write!(f, "\t{}\t{}", self.opcode.to_string(), operand),
-            _ => write!(f, "\t{}", self.opcode.to_string()),
+        write!(f, "{}", self.to_string_with(Dialect::default()))
+    }
+}
+
+/// The operation an instruction performs, which is either an opcode or a macro parameter
+/// that will be replaced with an opcode when the macro is expanded
+#[derive(PartialEq, Debug, Clone)]
+pub enum Operation<'a> {
+    Opcode(Opcode),
+    Parameter(Cow<'a, str>),
+}
+
+impl<'a> From<Opcode> for Operation<'a> {
+    fn from(opcode: Opcode) -> Self {
+        Operation::Opcode(opcode)
+    }
+}
+
+impl<'a> Display for Operation<'a> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Opcode(opcode) => write!(f, "{}", opcode),
+            Operation::Parameter(parameter) => write!(f, "{}", parameter),
         }
     }
 }
 
 /// Various opcodes
 #[allow(clippy::upper_case_acronyms)]
-#[derive(EnumVariantNames, EnumString, Display, PartialEq, Debug, Clone)]
-pub(crate) enum Opcode {
+#[derive(EnumVariantNames, EnumString, IntoStaticStr, Display, PartialEq, Debug, Clone)]
+pub enum Opcode {
     ADD,
     SUB,
+    #[strum(to_string = "STO", serialize = "STA")]
     STO,
     LDA,
     BRZ,
     BRP,
+    #[strum(to_string = "BR", serialize = "BRA")]
     BR,
+    #[strum(to_string = "IN", serialize = "INP")]
     IN,
     OUT,
+    OTC,
+    #[strum(to_string = "HLT", serialize = "COB")]
     HLT,
     DAT,
 }
 
+impl Opcode {
+    /// Every spelling of an opcode that can be parsed, including common aliases such as "STA" for "STO"
+    pub(crate) const MNEMONICS: &'static [&'static str] = &[
+        "ADD", "SUB", "STO", "STA", "LDA", "BRZ", "BRP", "BR", "BRA", "IN", "INP", "OUT", "OTC",
+        "HLT", "COB", "DAT",
+    ];
+
+    /// Returns true if the opcode can be given an operand, which every opcode other than input, output and halting can
+    pub fn takes_operand(&self) -> bool {
+        !matches!(self, Opcode::IN | Opcode::OUT | Opcode::OTC | Opcode::HLT)
+    }
+
+    /// Gets the name of the opcode, using the given spelling for opcodes with more than one name
+    pub fn mnemonic(&self, mnemonics: Mnemonics) -> &'static str {
+        match (mnemonics, self) {
+            (Mnemonics::Common, Opcode::STO) => "STA",
+            (Mnemonics::Common, Opcode::IN) => "INP",
+            (Mnemonics::Common, Opcode::BR) => "BRA",
+            (Mnemonics::Madnick, Opcode::HLT) => "COB",
+            _ => self.into(),
+        }
+    }
+}
+
+/// The spelling used when writing opcodes which have more than one name
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum Mnemonics {
+    /// The spelling from the Durham instruction set, such as "STO", "IN" and "BR"
+    #[default]
+    Durham,
+    /// The spelling used by most other LMC material and simulators, such as "STA", "INP" and "BRA"
+    Common,
+    /// The spelling from Madnick's original LMC, which is the same as Durham's other than "COB" for "HLT"
+    Madnick,
+}
+
 /// Matches a single instruction (optionally with a label), such as "label   ADD 10"
-pub(crate) fn parse_instruction(input: &str) -> IResult<&str, Instruction> {
+pub(crate) fn parse_instruction(input: &str, dialect: Dialect) -> IResult<&str, Instruction<'_>> {
     /// Matches one of the given strings (ignoring case), returning the first match
     fn alternative<'a>(input: &'a str, alternatives: &'a [&'a str]) -> IResult<&'a str, &'a str> {
         for alternative in alternatives {
@@ -97,43 +245,73 @@ pub(crate) fn parse_instruction(input: &str) -> IResult<&str, Instruction> {
         )))
     }
 
+    /// Matches a macro parameter used in place of an opcode, such as "$op"
+    fn parameter(input: &str) -> IResult<&str, &str> {
+        terminated(
+            recognize(pair(
+                tag("$"),
+                take_while1(|c| AsChar::is_alphanum(c) || c == '_'),
+            )),
+            peek(alt((multispace1, eof))),
+        )(input)
+    }
+
+    /// Matches an instruction's label
+    fn name(input: &str) -> IResult<&str, &str> {
+        take_while(|c| AsChar::is_alphanum(c) || ['_', '$'].contains(&c))(input)
+    }
+
     map_opt(
-        alt((
+        consumed(alt((
             // match format "[label] [opcode] [operand]?"
             map(
                 tuple((
-                    take_while(AsChar::is_alphanum),
-                    preceded(space1, |str| alternative(str, Opcode::VARIANTS)),
-                    opt(preceded(
-                        space0,
-                        take_while(|c| AsChar::is_alphanum(c) || ['_', '$'].contains(&c)),
-                    )),
+                    name,
+                    preceded(space1, |str| alternative(str, dialect.get_opcodes())),
+                    opt(preceded(space0, operand)),
                 )),
                 |(label, opcode, operand)| (Some(label), opcode, operand),
             ),
+            // match format "[label] [$parameter] [operand]", which needs an operand to tell it apart from
+            // "[$parameter] [operand]"
+            map(
+                tuple((
+                    name,
+                    preceded(space1, parameter),
+                    preceded(space1, verify(operand, |operand: &str| !operand.is_empty())),
+                )),
+                |(label, opcode, operand)| (Some(label), opcode, Some(operand)),
+            ),
             // match format "[opcode] [operand]?"
             map(
                 tuple((
-                    preceded(space0, |str| alternative(str, Opcode::VARIANTS)),
-                    opt(preceded(
+                    preceded(
                         space0,
-                        take_while(|c| AsChar::is_alphanum(c) || ['_', '$'].contains(&c)),
-                    )),
+                        alt((parameter, |str| alternative(str, dialect.get_opcodes()))),
+                    ),
+                    opt(preceded(space0, operand)),
                 )),
                 |(opcode, operand)| (None, opcode, operand),
             ),
-        )),
-        |(label, opcode, operand)| {
-            Opcode::from_str(opcode).ok().map(|opcode| {
+        ))),
+        |(fragment, (label, opcode, operand))| {
+            let operation = if opcode.starts_with('$') {
+                Some(Operation::Parameter(Cow::Borrowed(opcode)))
+            } else {
+                Opcode::from_str(opcode).ok().map(Operation::Opcode)
+            };
+
+            operation.map(|operation| {
                 Instruction::new(
                     label,
-                    opcode,
+                    operation,
                     if let Some("") = operand {
                         None
                     } else {
                         operand
                     },
                 )
+                .with_span(Span::of(fragment.trim_start()))
             })
         },
     )(input)
@@ -149,7 +327,7 @@ mod test {
             ($($input:expr => $label:expr, $opcode:expr, $operand:expr),*) => {
                 $(
                     assert_eq!(
-                        parse_instruction($input),
+                        parse_instruction($input, Dialect::default()),
                         Ok(("", Instruction::new(
                             $label,
                             $opcode,
@@ -167,7 +345,50 @@ mod test {
             "OUT" => None, Opcode::OUT, None,
             "IN" => None, Opcode::IN, None,
             "aaa IN" => Some("aaa"), Opcode::IN, None,
-            "abc DAT 10" => Some("abc"), Opcode::DAT, Some("10")
+            "abc DAT 10" => Some("abc"), Opcode::DAT, Some("10"),
+            "loop_2 BRZ done_2" => Some("loop_2"), Opcode::BRZ, Some("done_2"),
+            "$target DAT 0" => Some("$target"), Opcode::DAT, Some("0"),
+            "$op $operand" => None, Operation::Parameter("$op".into()), Some("$operand"),
+            "$op" => None, Operation::Parameter("$op".into()), None,
+            "$loop $op 10" => Some("$loop"), Operation::Parameter("$op".into()), Some("10"),
+            "STA a" => None, Opcode::STO, Some("a"),
+            "INP" => None, Opcode::IN, None,
+            "loop BRA loop" => Some("loop"), Opcode::BR, Some("loop"),
+            "OTC" => None, Opcode::OTC, None,
+            "COB" => None, Opcode::HLT, None,
+            "LDA table+3" => None, Opcode::LDA, Some("table+3"),
+            "end DAT end-start" => Some("end"), Opcode::DAT, Some("end-start")
+        );
+    }
+
+    #[test]
+    fn test_instruction_parser_dialects() {
+        assert!(parse_instruction("STO a", Dialect::durham()).is_ok());
+        assert!(parse_instruction("STA a", Dialect::durham()).is_err());
+        assert!(parse_instruction("OTC", Dialect::durham()).is_err());
+        assert!(parse_instruction("STA a", Dialect::higginson()).is_ok());
+        assert!(parse_instruction("STO a", Dialect::higginson()).is_err());
+        assert!(parse_instruction("COB", Dialect::madnick()).is_ok());
+        assert!(parse_instruction("HLT", Dialect::madnick()).is_err());
+    }
+
+    #[test]
+    fn test_instruction_mnemonics() {
+        let instruction = Instruction::new(Some("a"), Opcode::STO, Some("b"));
+
+        assert_eq!(instruction.to_string(), "a\tSTO\tb");
+        assert_eq!(instruction.to_string_with(Dialect::durham()), "a\tSTO\tb");
+        assert_eq!(
+            instruction.to_string_with(Dialect::higginson()),
+            "a\tSTA\tb"
+        );
+        assert_eq!(
+            instruction.to_string_with(Dialect::durham().with_mnemonics(Mnemonics::Common)),
+            "a\tSTA\tb"
+        );
+        assert_eq!(
+            Instruction::new(None, Opcode::HLT, None).to_string_with(Dialect::madnick()),
+            "\tCOB"
         );
     }
 }
@@ -1,52 +1,70 @@
+use std::borrow::Cow;
+
 use nom::{
     bytes::complete::tag,
-    character::complete::{alpha1, multispace0},
-    combinator::map,
+    character::complete::multispace0,
+    combinator::{consumed, map},
     multi::separated_list0,
     sequence::{delimited, pair},
     IResult,
 };
 
-use super::super::identifier;
+use super::super::{expression::argument, identifier};
+use crate::diagnostics::Span;
 
 /// Stores information about a single macro call
 #[derive(PartialEq, Debug, Clone)]
-pub(crate) struct MacroCall<'a> {
+pub struct MacroCall<'a> {
     identifier: &'a str,
-    arguments: Vec<&'a str>,
+    arguments: Vec<Cow<'a, str>>,
+    span: Span,
 }
 
 impl<'a> MacroCall<'a> {
     /// Creates a new macro call from the given information
-    pub(crate) fn new(identifier: &'a str, arguments: Vec<&'a str>) -> Self {
+    pub fn new(identifier: &'a str, arguments: Vec<Cow<'a, str>>) -> Self {
         Self {
             identifier,
             arguments,
+            span: Span::default(),
         }
     }
 
+    /// Sets the span of source text the macro call was parsed from
+    pub(crate) fn with_span(self, span: Span) -> Self {
+        Self { span, ..self }
+    }
+
     /// Gets the macro calls identifier
-    pub(crate) fn get_identifier(&self) -> &'a str {
+    pub fn get_identifier(&self) -> &'a str {
         self.identifier
     }
 
-    pub(crate) fn get_arguments(&self) -> &Vec<&'a str> {
+    /// Gets the macro calls arguments
+    pub fn get_arguments(&self) -> &Vec<Cow<'a, str>> {
         &self.arguments
     }
+
+    /// Gets the span of source text the macro call was parsed from
+    pub fn get_span(&self) -> Span {
+        self.span
+    }
 }
 
 /// Parses a single macro call, such as "IN_STO!(a)"
-pub(crate) fn macro_call(input: &str) -> IResult<&str, MacroCall> {
+pub(crate) fn macro_call(input: &str) -> IResult<&str, MacroCall<'_>> {
     map(
-        pair(
+        consumed(pair(
             identifier,
             delimited(
                 tag("!("),
-                separated_list0(pair(tag(","), multispace0), alpha1),
+                separated_list0(pair(tag(","), multispace0), map(argument, Cow::Borrowed)),
                 tag(")"),
             ),
-        ),
-        |(identifier, arguments)| MacroCall::new(identifier, arguments),
+        )),
+        |(fragment, (identifier, arguments))| {
+            MacroCall::new(identifier, arguments).with_span(Span::of(fragment))
+        },
     )(input)
 }
 
@@ -90,6 +108,32 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_macro_substitute_label() {
+        let macro_defn = MacroDeclaration::new(
+            "VAR",
+            vec!["$name", "$entry"],
+            vec![
+                Item::Instruction(Instruction::new(Some("$entry"), Opcode::LDA, Some("$name"))),
+                Item::Instruction(Instruction::new(None, Opcode::HLT, None)),
+                Item::Instruction(Instruction::new(Some("$name"), Opcode::DAT, Some("0"))),
+            ],
+        );
+
+        assert_eq!(
+            macro_defn.substitute_arguments(&["counter", "start"]),
+            Some(vec![
+                Item::Instruction(Instruction::new(
+                    Some("start"),
+                    Opcode::LDA,
+                    Some("counter")
+                )),
+                Item::Instruction(Instruction::new(None, Opcode::HLT, None)),
+                Item::Instruction(Instruction::new(Some("counter"), Opcode::DAT, Some("0"))),
+            ])
+        );
+    }
+
     #[test]
     fn test_macro_call_parsing() {
         let macro_call_str = "IN_STO!(a, b)";
@@ -102,7 +146,8 @@ mod test {
             macro_call_parsed,
             MacroCall {
                 identifier: "IN_STO",
-                arguments: vec!["a", "b"]
+                arguments: vec!["a".into(), "b".into()],
+                span: Span::default(),
             }
         );
     }
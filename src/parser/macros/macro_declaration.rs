@@ -1,55 +1,176 @@
-use std::collections::HashMap;
+use std::{borrow::Cow, collections::HashMap, ops::RangeInclusive};
 
 use nom::{
+    branch::alt,
     bytes::complete::tag,
-    character::complete::multispace0,
-    combinator::{map, opt, recognize},
+    character::complete::{multispace0, space0},
+    combinator::{consumed, cut, map, opt, recognize, verify},
     multi::separated_list0,
     sequence::{delimited, pair, preceded, tuple},
     IResult,
 };
 
 use super::{
-    super::{identifier, Item},
+    super::{
+        dialect::Dialect,
+        expression::{argument, substitute_names},
+        identifier,
+        instruction::Operation,
+        Item,
+    },
     macro_call::MacroCall,
 };
+use crate::diagnostics::Span;
 
 /// Stores information about a single macro declaration.
 #[derive(PartialEq, Debug, Clone)]
-pub(crate) struct MacroDeclaration<'a> {
+pub struct MacroDeclaration<'a> {
     identifier: &'a str,
     arguments: Vec<&'a str>,
+    defaults: Vec<Option<&'a str>>,
+    variadic: bool,
     body: Vec<Item<'a>>,
+    span: Span,
 }
 
 impl<'a> MacroDeclaration<'a> {
     /// Creates a new macro declaration from the given information
-    pub(crate) fn new(identifier: &'a str, arguments: Vec<&'a str>, body: Vec<Item<'a>>) -> Self {
+    pub fn new(identifier: &'a str, arguments: Vec<&'a str>, body: Vec<Item<'a>>) -> Self {
         Self {
             identifier,
+            defaults: vec![None; arguments.len()],
+            variadic: false,
             arguments,
             body,
+            span: Span::default(),
+        }
+    }
+
+    /// Sets the default value of each argument, used when a call leaves it out.
+    /// There must be exactly one for each argument.
+    pub(crate) fn with_defaults(self, defaults: Vec<Option<&'a str>>) -> Self {
+        Self { defaults, ..self }
+    }
+
+    /// Sets whether the last argument is variadic, taking every argument left over once the others are given.
+    /// The macro must have at least one argument to be variadic.
+    pub(crate) fn with_variadic(self, variadic: bool) -> Self {
+        Self { variadic, ..self }
+    }
+
+    /// Sets the span of source text the macro declaration was parsed from
+    pub(crate) fn with_span(self, span: Span) -> Self {
+        Self { span, ..self }
+    }
+
+    /// Creates a new macro declaration identical to the current one, but with a different body
+    pub(crate) fn clone_with_body(&self, body: Vec<Item<'a>>) -> Self {
+        Self {
+            body,
+            ..self.clone()
         }
     }
 
     /// Gets the macro declaration's identifier
-    pub(crate) fn get_identifier(&self) -> &str {
+    pub fn get_identifier(&self) -> &'a str {
         self.identifier
     }
 
+    /// Gets the macro declaration's arguments
+    pub fn get_arguments(&self) -> &Vec<&'a str> {
+        &self.arguments
+    }
+
+    /// Gets the default value of each of the macro declaration's arguments, if it has one
+    pub fn get_defaults(&self) -> &Vec<Option<&'a str>> {
+        &self.defaults
+    }
+
+    /// Returns true if the last argument is variadic, such as "$rest..."
+    pub fn is_variadic(&self) -> bool {
+        self.variadic
+    }
+
+    /// Gets the range of argument counts the macro can be called with,
+    /// where trailing arguments with a default value can be left out and a variadic argument takes any number
+    pub fn get_arity(&self) -> RangeInclusive<usize> {
+        let fixed = self.fixed_arguments();
+        let optional = self.defaults[..fixed]
+            .iter()
+            .rev()
+            .take_while(|default| default.is_some())
+            .count();
+        let maximum = if self.variadic { usize::MAX } else { fixed };
+
+        fixed - optional..=maximum
+    }
+
+    /// Gets the number of arguments other than the variadic one
+    fn fixed_arguments(&self) -> usize {
+        self.arguments.len() - usize::from(self.variadic)
+    }
+
+    /// Gets the macro declaration's body
+    pub fn get_body(&self) -> &Vec<Item<'a>> {
+        &self.body
+    }
+
+    /// Gets the span of source text the macro declaration was parsed from
+    pub fn get_span(&self) -> Span {
+        self.span
+    }
+
+    /// Gets the labels defined in the macro's body which are local to each expansion.
+    /// This is every label defined by an instruction in the body, other than parameters and labels declared `global`.
+    pub fn get_local_labels(&self) -> Vec<&str> {
+        local_labels(&self.body)
+    }
+
     /// Substitutes the given arguments into the macro, replacing all occurences with the same index.
-    /// If the lengths of the new arguments and existing arguments do not match, None will be returned.
-    pub(crate) fn substitute_arguments(&self, new_args: &[&'a str]) -> Option<Vec<Item<'a>>> {
-        // will only work if same number of arguments
-        if new_args.len() != self.arguments.len() {
+    /// Any arguments left out are given their default value,
+    /// but if the number of new arguments isn't in [`Self::get_arity`], None will be returned.
+    /// Arguments used as opcodes are left as written, to be checked against the dialect when the macro is expanded.
+    pub fn substitute_arguments(&self, new_args: &[&'a str]) -> Option<Vec<Item<'a>>> {
+        let new_args: Vec<_> = new_args.iter().map(|&arg| Cow::Borrowed(arg)).collect();
+        self.substitute(&new_args, &HashMap::new())
+    }
+
+    /// Substitutes the given arguments into the macro like [`Self::substitute_arguments`],
+    /// also renaming any labels in the given map so each expansion can have its own copy of them.
+    pub(crate) fn substitute(
+        &self,
+        new_args: &[Cow<'a, str>],
+        renames: &HashMap<&str, String>,
+    ) -> Option<Vec<Item<'a>>> {
+        // will only work if every argument without a default is given
+        if !self.get_arity().contains(&new_args.len()) {
             return None;
         }
 
-        // create a map of macro declaration arg names -> replacement arg names
+        // create a map of macro declaration arg names -> replacement arg names, along with any renamed labels
         let arg_map = {
-            let mut map = HashMap::with_capacity(self.arguments.len());
-            for (&a, &b) in self.arguments.iter().zip(new_args.iter()) {
-                map.insert(a, b);
+            let mut map = HashMap::with_capacity(self.arguments.len() + renames.len());
+            for (old, new) in renames {
+                map.insert(*old, Argument::Value(Cow::Owned(new.clone())));
+            }
+            let fixed = self.fixed_arguments();
+            for (index, &a) in self.arguments[..fixed].iter().enumerate() {
+                let b = match new_args.get(index) {
+                    Some(b) => b.clone(),
+                    // defaults can refer to earlier arguments, so are substituted too
+                    None => {
+                        let default = self.defaults[index]?;
+                        substitute_names(default, |name| Argument::value(&map, name))
+                            .unwrap_or(Cow::Borrowed(default))
+                    }
+                };
+                map.insert(a, Argument::Value(b));
+            }
+
+            // the variadic argument is replaced with the rest of the arguments wherever a list is expected
+            if self.variadic {
+                let rest = new_args.get(fixed..).unwrap_or_default().to_vec();
+                map.insert(self.arguments[fixed], Argument::List(rest));
             }
 
             map
@@ -65,70 +186,216 @@ impl<'a> MacroDeclaration<'a> {
     }
 }
 
+/// Gets the labels defined by instructions in a body, other than parameters and labels declared `global`,
+/// along with the names of any `local` storage
+pub(crate) fn local_labels<'b>(body: &'b [Item]) -> Vec<&'b str> {
+    let globals: Vec<_> = body
+        .iter()
+        .filter_map(|item| match item {
+            Item::Global(labels, _) => Some(labels),
+            _ => None,
+        })
+        .flatten()
+        .collect();
+
+    let locals = body.iter().filter_map(|item| match item {
+        Item::Local(name, _, _) => Some(name.as_ref()),
+        _ => None,
+    });
+
+    body.iter()
+        .filter_map(|item| match item {
+            Item::Instruction(instruction) => instruction.get_label(),
+            _ => None,
+        })
+        .filter(|label| !label.starts_with('$') && !globals.contains(&label))
+        .chain(locals)
+        .collect()
+}
+
+/// The value a parameter is replaced with, which is a list of values for a variadic parameter
+#[derive(Debug, Clone)]
+pub(crate) enum Argument<'a> {
+    Value(Cow<'a, str>),
+    List(Vec<Cow<'a, str>>),
+}
+
+impl<'a> Argument<'a> {
+    /// Gets the single value a name is replaced with, if it has one.
+    /// Variadic parameters can only be used as a list, so never have one.
+    fn value(argument_map: &HashMap<&str, Argument<'a>>, name: &str) -> Option<Cow<'a, str>> {
+        match argument_map.get(name) {
+            Some(Argument::Value(value)) => Some(value.clone()),
+            _ => None,
+        }
+    }
+}
+
 /// Substitutes the arguments in a macro call for a single item.
-fn substitute_argument_item<'a>(
+pub(crate) fn substitute_argument_item<'a>(
     item: &Item<'a>,
-    argument_map: &HashMap<&'a str, &'a str>,
+    argument_map: &HashMap<&str, Argument<'a>>,
 ) -> Item<'a> {
+    let value = |name: &str| Argument::value(argument_map, name);
+
     match item {
         Item::Instruction(instruction) => {
-            // easy case, just check if argument is in map, and replace if so
-            match argument_map.get(instruction.get_operand().unwrap_or_default()) {
-                Some(new_arg) => Item::Instruction(instruction.clone_with_operand(new_arg)),
-                None => item.clone(),
+            // easy case, just check if label or operand is in map, and replace if so
+            let mut instruction = instruction.clone();
+
+            if let Some(new_label) = instruction.get_label().and_then(value) {
+                instruction = instruction.clone_with_label(new_label);
+            }
+
+            // operands can be expressions, so each name in them is replaced separately
+            let operand = instruction
+                .get_operand()
+                .and_then(|operand| substitute_names(operand, value));
+            if let Some(new_arg) = operand {
+                instruction = instruction.clone_with_operand(new_arg);
+            }
+
+            // opcodes are replaced with the argument as written, which is checked against the dialect once expanded
+            let operation = match instruction.get_operation() {
+                Operation::Parameter(parameter) => value(parameter),
+                _ => None,
+            };
+            if let Some(new_arg) = operation {
+                instruction = instruction.clone_with_operation(Operation::Parameter(new_arg));
             }
+
+            Item::Instruction(instruction)
         }
         Item::MacroCall(macro_call) => {
             // slightly more tricky as can have multiple arguments, but basically repeat above for each argument
-            let arguments = macro_call
-                .get_arguments()
+            let arguments = substitute_list(macro_call.get_arguments(), argument_map);
+
+            // then can just reconstruct a macro call
+            Item::MacroCall(
+                MacroCall::new(macro_call.get_identifier(), arguments)
+                    .with_span(macro_call.get_span()),
+            )
+        }
+        Item::Repeat(repeat) => {
+            // the count can use parameters, and the body is substituted just like the macro's body
+            let count = substitute_names(repeat.get_count(), value)
+                .unwrap_or_else(|| repeat.get_count().to_string().into());
+            let body = repeat
+                .get_body()
                 .iter()
-                .map(|argument| *argument_map.get(argument).unwrap_or(argument))
+                .map(|item| substitute_argument_item(item, argument_map))
                 .collect();
 
-            // then can just reconstruct a macro call
-            Item::MacroCall(MacroCall::new(macro_call.get_identifier(), arguments))
+            Item::Repeat(repeat.clone_with(count, body))
+        }
+        Item::ForEach(for_each) => {
+            let values = substitute_list(for_each.get_values(), argument_map);
+            let body = for_each
+                .get_body()
+                .iter()
+                .map(|item| substitute_argument_item(item, argument_map))
+                .collect();
+
+            Item::ForEach(for_each.clone_with(values, body))
+        }
+        Item::Local(name, local_value, span) => {
+            let new_name = value(name).unwrap_or_else(|| name.clone());
+            let new_value = local_value.as_ref().map(|local_value| {
+                substitute_names(local_value, value).unwrap_or_else(|| local_value.clone())
+            });
+
+            Item::Local(new_name, new_value, *span)
         }
-        Item::Comment(comment) => {
+        Item::Comment(comment, span) => {
             let mut new_comment = comment.to_string();
 
-            for (old, new) in argument_map {
-                new_comment = new_comment.replace(old, new);
+            // only parameters are replaced, as renamed labels could easily match normal words
+            for (old, new) in argument_map.iter().filter(|(old, _)| old.starts_with('$')) {
+                let new = match new {
+                    Argument::Value(value) => value.to_string(),
+                    Argument::List(values) => values.join(", "),
+                };
+                new_comment = new_comment.replace(old, &new);
             }
 
-            Item::Comment(new_comment)
+            Item::Comment(new_comment, *span)
         }
         _ => item.clone(),
     }
 }
 
+/// Substitutes the arguments into a list, such as the arguments of a macro call,
+/// where a variadic argument is replaced with each of the arguments it was given
+fn substitute_list<'a>(
+    list: &[Cow<'a, str>],
+    argument_map: &HashMap<&str, Argument<'a>>,
+) -> Vec<Cow<'a, str>> {
+    list.iter()
+        .flat_map(|item| match argument_map.get(item.as_ref()) {
+            Some(Argument::List(values)) => values.clone(),
+            Some(Argument::Value(value)) => vec![value.clone()],
+            None => vec![item.clone()],
+        })
+        .collect()
+}
+
 /// Matches a macro declaration
-pub(crate) fn macro_declaration(input: &str) -> IResult<&str, MacroDeclaration> {
+pub(crate) fn macro_declaration(
+    input: &str,
+    dialect: Dialect,
+) -> IResult<&str, MacroDeclaration<'_>> {
     // a macro declaration looks like
-    // macro IDENTIFIER(ARGUMENTS, ARGUMENTS, ...) => {
+    // macro IDENTIFIER(ARGUMENTS, ARGUMENTS = DEFAULT, ..., VARIADIC...) => {
     //     PROGRAM
     // }
     map(
-        tuple((
+        consumed(tuple((
             // matches the identifier
             preceded(tag("macro "), identifier),
-            // matches the argument list
+            // matches the argument list, where only the last argument can be variadic
             delimited(
                 tag("("),
-                separated_list0(
-                    pair(tag(","), opt(multispace0)),
-                    recognize(pair(tag("$"), identifier)),
+                verify(
+                    separated_list0(
+                        pair(tag(","), opt(multispace0)),
+                        pair(
+                            recognize(pair(tag("$"), identifier)),
+                            alt((
+                                map(tag("..."), |_| (None, true)),
+                                map(
+                                    opt(preceded(tuple((space0, tag("="), space0)), argument)),
+                                    |default| (default, false),
+                                ),
+                            )),
+                        ),
+                    ),
+                    |arguments: &[_]| {
+                        let fixed = arguments.len().saturating_sub(1);
+                        arguments[..fixed]
+                            .iter()
+                            .all(|(_, (_, variadic))| !variadic)
+                    },
                 ),
                 tag(")"),
             ),
-            // matches the macro body
+            // matches the macro body, which must be closed once it has been opened
             delimited(
                 tuple((multispace0, tag("="), multispace0, (tag("{")))),
-                super::super::parse_program,
-                pair(multispace0, tag("}")),
+                |input| super::super::parse_program(input, dialect),
+                cut(pair(multispace0, tag("}"))),
             ),
-        )),
-        |(identifier, arguments, body)| MacroDeclaration::new(identifier, arguments, body),
+        ))),
+        |(fragment, (identifier, arguments, body))| {
+            let variadic = arguments.last().is_some_and(|(_, (_, variadic))| *variadic);
+            let (arguments, defaults) = arguments
+                .into_iter()
+                .map(|(argument, (default, _))| (argument, default))
+                .unzip();
+            MacroDeclaration::new(identifier, arguments, body)
+                .with_defaults(defaults)
+                .with_variadic(variadic)
+                .with_span(Span::of(fragment))
+        },
     )(input)
 }
 
@@ -144,7 +411,7 @@ mod test {
             STO $location
         }";
 
-        let macro_parsed = macro_declaration(macro_str);
+        let macro_parsed = macro_declaration(macro_str, Dialect::default());
         println!("{:?}", macro_parsed);
         assert!(macro_parsed.is_ok());
         let macro_parsed = macro_parsed.unwrap().1;
@@ -161,4 +428,58 @@ mod test {
             )
         );
     }
+
+    #[test]
+    fn test_default_arguments() {
+        let macro_str = "macro COPY($src, $dst = acc_tmp, $next = $dst+1) = {
+            LDA $src
+            STO $dst
+            STO $next
+        }";
+
+        let macro_parsed = macro_declaration(macro_str, Dialect::default()).unwrap().1;
+        assert_eq!(
+            macro_parsed.get_defaults(),
+            &[None, Some("acc_tmp"), Some("$dst+1")]
+        );
+        assert_eq!(macro_parsed.get_arity(), 1..=3);
+
+        assert_eq!(
+            macro_parsed.substitute_arguments(&["a"]),
+            Some(vec![
+                Item::Instruction(Instruction::new(None, Opcode::LDA, Some("a"))),
+                Item::Instruction(Instruction::new(None, Opcode::STO, Some("acc_tmp"))),
+                Item::Instruction(Instruction::new(None, Opcode::STO, Some("acc_tmp+1"))),
+            ])
+        );
+        assert_eq!(macro_parsed.substitute_arguments(&[]), None);
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let macro_str = "macro COUNT_DOWN($from) = {
+            global done
+            LDA $from
+            loop SUB one
+            BRZ done
+            BR loop
+            done OUT
+        }";
+
+        let macro_parsed = macro_declaration(macro_str, Dialect::default()).unwrap().1;
+        assert_eq!(macro_parsed.get_local_labels(), vec!["loop"]);
+
+        let renames = vec![("loop", "loop_1".to_string())].into_iter().collect();
+        assert_eq!(
+            macro_parsed.substitute(&["a".into()], &renames),
+            Some(vec![
+                Item::Global(vec!["done"], Span::default()),
+                Item::Instruction(Instruction::new(None, Opcode::LDA, Some("a"))),
+                Item::Instruction(Instruction::new(Some("loop_1"), Opcode::SUB, Some("one"))),
+                Item::Instruction(Instruction::new(None, Opcode::BRZ, Some("done"))),
+                Item::Instruction(Instruction::new(None, Opcode::BR, Some("loop_1"))),
+                Item::Instruction(Instruction::new(Some("done"), Opcode::OUT, None)),
+            ])
+        );
+    }
 }
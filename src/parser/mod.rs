@@ -1,78 +1,501 @@
-mod instruction;
+pub(crate) mod dialect;
+pub(crate) mod directive;
+pub(crate) mod expression;
+pub(crate) mod for_each;
+pub(crate) mod instruction;
 pub(crate) mod macros;
+pub(crate) mod repeat;
 
 use self::{
     macros::macro_call::{macro_call, MacroCall},
     macros::macro_declaration::{macro_declaration, MacroDeclaration},
 };
-use instruction::Instruction;
+use crate::diagnostics::{Diagnostic, Span};
+use dialect::Dialect;
+use directive::{directive, Condition, Section};
+use for_each::{for_each, ForEach};
+use instruction::{Instruction, Opcode};
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::{multispace0, not_line_ending},
-    combinator::map,
-    multi::many0,
-    sequence::preceded,
+    bytes::complete::{tag, take_while, take_while1},
+    character::complete::{multispace0, not_line_ending, space0, space1},
+    combinator::{consumed, map, opt, recognize, verify},
+    multi::{many0, separated_list1},
+    sequence::{pair, preceded, tuple},
     AsChar, IResult,
 };
-use std::fmt::{self, Display, Formatter};
+use repeat::{repeat, Repeat};
+use std::{
+    borrow::Cow,
+    fmt::{self, Display, Formatter},
+};
 
+/// A single top-level item in a program
 #[derive(PartialEq, Debug, Clone)]
-pub(crate) enum Item<'a> {
+pub enum Item<'a> {
     Instruction(Instruction<'a>),
     MacroDeclaration(MacroDeclaration<'a>),
     MacroCall(MacroCall<'a>),
-    Comment(String),
+    Repeat(Repeat<'a>),
+    ForEach(ForEach<'a>),
+    Comment(String, Span),
+    Global(Vec<&'a str>, Span),
+    Local(Cow<'a, str>, Option<Cow<'a, str>>, Span),
+    Include(&'a str, Span),
+    Use(&'a str, Span),
+    Constant(&'a str, &'a str, Span),
+    Define(&'a str, Option<&'a str>, Span),
+    If(Condition<'a>, Span),
+    Else(Span),
+    EndIf(Span),
+    Section(Section, Span),
+}
+
+impl<'a> Item<'a> {
+    /// Gets the span of source text the item was parsed from
+    pub fn get_span(&self) -> Span {
+        match self {
+            Item::Instruction(instruction) => instruction.get_span(),
+            Item::MacroDeclaration(declaration) => declaration.get_span(),
+            Item::MacroCall(call) => call.get_span(),
+            Item::Repeat(repeat) => repeat.get_span(),
+            Item::ForEach(for_each) => for_each.get_span(),
+            Item::Comment(_, span)
+            | Item::Global(_, span)
+            | Item::Local(_, _, span)
+            | Item::Include(_, span)
+            | Item::Use(_, span)
+            | Item::Constant(_, _, span)
+            | Item::Define(_, _, span)
+            | Item::If(_, span)
+            | Item::Else(span)
+            | Item::EndIf(span)
+            | Item::Section(_, span) => *span,
+        }
+    }
+}
+
+impl<'a> Item<'a> {
+    /// Formats the item the way the given dialect writes it
+    pub fn to_string_with(&self, dialect: Dialect) -> String {
+        match self {
+            Item::Instruction(instruction) => instruction.to_string_with(dialect),
+            Item::Comment(comment, _) => format!("{}{}", dialect.get_comments()[0], comment),
+            // sections only have an effect when the program is laid out, so are otherwise kept as a reminder
+            Item::Section(section, _) => format!("{} {}", dialect.get_comments()[0], section),
+            _ => String::new(),
+        }
+    }
 }
 
 impl<'a> Display for Item<'a> {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        match self {
-            Item::Instruction(instruction) => write!(f, "{}", instruction),
-            Item::Comment(comment) => write!(f, "#{}", comment),
-            _ => write!(f, ""),
+        write!(f, "{}", self.to_string_with(Dialect::default()))
+    }
+}
+
+/// Matches a comment started by any of the dialect's comment markers, such as "# this is a comment"
+fn comment(input: &str, dialect: Dialect) -> IResult<&str, &str> {
+    for marker in dialect.get_comments() {
+        if let Some(rest) = input.strip_prefix(marker) {
+            return not_line_ending(rest);
         }
     }
+
+    Err(nom::Err::Error(nom::error::Error::new(
+        input,
+        nom::error::ErrorKind::Tag,
+    )))
 }
 
-/// Matches a comment, such as "# this is a comment"
-fn comment(input: &str) -> IResult<&str, &str> {
-    preceded(tag("#"), not_line_ending)(input)
+/// Matches a declaration of labels which are global within a macro body, such as "global done, end"
+fn global(input: &str) -> IResult<&str, Vec<&str>> {
+    preceded(
+        pair(tag("global"), space1),
+        separated_list1(pair(tag(","), space0), identifier),
+    )(input)
 }
 
-/// Matches valid identifiers, such as "aaa_b"
+/// Matches a declaration of storage local to a macro body, such as "local $tmp" or "local $count = 10"
+fn local(input: &str) -> IResult<&str, (&str, Option<&str>)> {
+    preceded(
+        pair(tag("local"), space1),
+        pair(
+            recognize(pair(tag("$"), identifier)),
+            opt(preceded(
+                tuple((space0, tag("="), space0)),
+                verify(expression::operand, |value: &str| !value.is_empty()),
+            )),
+        ),
+    )(input)
+}
+
+/// Matches valid identifiers, such as "aaa_b" or "add_2"
 fn identifier(input: &str) -> IResult<&str, &str> {
-    take_while1(|c: char| c.is_alpha() || c == '_')(input)
+    recognize(pair(
+        take_while1(|c: char| c.is_alpha() || c == '_'),
+        take_while(|c: char| c.is_alphanum() || c == '_'),
+    ))(input)
 }
 
 /// Parses an entire program, returning a vector of instructions and discarding comments
-pub(crate) fn parse_program(input: &str) -> IResult<&str, Vec<Item>> {
-    // a program consists of many (macro declarations, macro calls, instructions, comments) delimeted by spaces/newlines
+pub(crate) fn parse_program(input: &str, dialect: Dialect) -> IResult<&str, Vec<Item<'_>>> {
+    // a program consists of many (macro declarations, macro calls, repeat and for each blocks, directives, instructions, comments)
+    // delimeted by spaces/newlines
     many0(preceded(
         multispace0,
         alt((
             // depending on the type of item matched, put in correct item enum
-            map(comment, |comment| Item::Comment(comment.to_string())),
-            map(macro_declaration, Item::MacroDeclaration),
+            map(
+                consumed(|input| comment(input, dialect)),
+                |(fragment, comment)| Item::Comment(comment.to_string(), Span::of(fragment)),
+            ),
+            map(
+                |input| macro_declaration(input, dialect),
+                Item::MacroDeclaration,
+            ),
             map(macro_call, Item::MacroCall),
-            map(instruction::parse_instruction, Item::Instruction),
+            map(|input| repeat(input, dialect), Item::Repeat),
+            map(|input| for_each(input, dialect), Item::ForEach),
+            // instructions come before keywords, so a keyword used as a label such as "global OUT" stays an instruction
+            map(
+                |input| instruction::parse_instruction(input, dialect),
+                Item::Instruction,
+            ),
+            map(consumed(global), |(fragment, labels)| {
+                Item::Global(labels, Span::of(fragment))
+            }),
+            map(consumed(local), |(fragment, (name, value))| {
+                Item::Local(name.into(), value.map(Cow::Borrowed), Span::of(fragment))
+            }),
+            directive,
         )),
     ))(input)
 }
 
+/// Parses an entire program, which must consume all of the input.
+/// If parsing stops early, a diagnostic is returned describing where and why.
+pub(crate) fn parse(input: &str, dialect: Dialect) -> Result<Vec<Item<'_>>, Diagnostic> {
+    match parse_program(input, dialect) {
+        Ok((rest, program)) if rest.trim().is_empty() => Ok(program),
+        Ok((rest, _)) => Err(unparsed(input, rest, dialect)),
+        Err(nom::Err::Error(error)) | Err(nom::Err::Failure(error)) => {
+            Err(unparsed(input, error.input, dialect))
+        }
+        Err(nom::Err::Incomplete(_)) => Err(unparsed(input, &input[input.len()..], dialect)),
+    }
+}
+
+/// Checks a parsed program follows the dialect's rules, such as labels not sharing a name with an opcode
+pub(crate) fn validate(program: &[Item], dialect: Dialect) -> Vec<Diagnostic> {
+    let mut errors = Vec::new();
+
+    for item in program {
+        match item {
+            Item::Instruction(instruction) => {
+                let label = instruction.get_label().unwrap_or_default();
+                if !dialect.allows_opcode_labels() && dialect.accepts_opcode(label) {
+                    errors.push(Diagnostic::new(
+                        format!(
+                            "label `{}` has the same name as an opcode, which the {} dialect does not allow",
+                            label,
+                            dialect.get_name()
+                        ),
+                        instruction.get_span(),
+                    ));
+                }
+            }
+            Item::MacroDeclaration(declaration) => {
+                // arguments can only be left out from the end of a call, so only trailing ones can have defaults
+                let defaults = declaration.get_defaults();
+                let defaults = &defaults[..defaults.len() - usize::from(declaration.is_variadic())];
+                let missing = defaults.iter().position(Option::is_some).and_then(|first| {
+                    let missing = defaults[first..].iter().position(Option::is_none)?;
+                    Some((first, first + missing))
+                });
+                if let Some((first, missing)) = missing {
+                    let arguments = declaration.get_arguments();
+                    errors.push(Diagnostic::new(
+                        format!(
+                            "argument `{}` of macro `{}` needs a default value, as it comes after `{}` which has one",
+                            arguments[missing],
+                            declaration.get_identifier(),
+                            arguments[first]
+                        ),
+                        declaration.get_span(),
+                    ));
+                }
+
+                // a variadic parameter stands for a list, so can't be used where only a single value fits
+                let variadic = declaration
+                    .get_arguments()
+                    .last()
+                    .filter(|_| declaration.is_variadic());
+                if let Some(parameter) = variadic {
+                    let mut uses = Vec::new();
+                    misused_list(declaration.get_body(), parameter, &mut uses);
+                    errors.extend(uses.into_iter().map(|span| {
+                        Diagnostic::new(
+                            format!(
+                                "variadic parameter `{}` can only be used as a `for` list or a macro call argument",
+                                parameter
+                            ),
+                            span,
+                        )
+                        .with_note(
+                            format!("declared by macro `{}` here", declaration.get_identifier()),
+                            declaration.get_span(),
+                        )
+                    }));
+                }
+
+                errors.extend(validate(declaration.get_body(), dialect))
+            }
+            Item::Repeat(repeat) => errors.extend(validate(repeat.get_body(), dialect)),
+            Item::ForEach(for_each) => errors.extend(validate(for_each.get_body(), dialect)),
+            _ => {}
+        }
+    }
+
+    errors
+}
+
+/// Finds the span of each item in a body which uses the given list parameter somewhere other than
+/// as a whole `for` list or macro call argument, such as in an operand, label or opcode
+fn misused_list(body: &[Item], parameter: &str, uses: &mut Vec<Span>) {
+    let contains = |text: &str| {
+        let mut found = false;
+        expression::substitute_names(text, |name| {
+            found |= name == parameter;
+            None::<Cow<str>>
+        });
+        found
+    };
+    let in_list = |list: &[Cow<str>]| {
+        list.iter()
+            .any(|value| value != parameter && contains(value))
+    };
+
+    for item in body {
+        let misused = match item {
+            Item::Instruction(instruction) => {
+                instruction.get_label() == Some(parameter)
+                    || matches!(instruction.get_operation(), instruction::Operation::Parameter(name) if name == parameter)
+                    || instruction.get_operand().is_some_and(contains)
+            }
+            Item::MacroCall(call) => in_list(call.get_arguments()),
+            Item::Repeat(repeat) => {
+                misused_list(repeat.get_body(), parameter, uses);
+                contains(repeat.get_count())
+            }
+            Item::ForEach(for_each) => {
+                misused_list(for_each.get_body(), parameter, uses);
+                in_list(for_each.get_values())
+            }
+            Item::Local(name, value, _) => {
+                name == parameter || value.as_deref().is_some_and(contains)
+            }
+            _ => false,
+        };
+
+        if misused {
+            uses.push(item.get_span());
+        }
+    }
+}
+
+/// Creates a diagnostic explaining why the rest of the input could not be parsed
+fn unparsed<'a>(input: &'a str, rest: &'a str, dialect: Dialect) -> Diagnostic {
+    let rest = rest.trim_start();
+    // if parsing stopped partway through a line, the start of the line was fine
+    let preceding = input[..input.len() - rest.len()].trim_end_matches([' ', '\t']);
+    let mid_line = !preceding.is_empty() && !preceding.ends_with('\n');
+
+    // only look at the first line, ignoring any comment on it
+    let line = rest.lines().next().unwrap_or_default();
+    let line = dialect
+        .get_comments()
+        .iter()
+        .fold(line, |line, marker| {
+            line.split(marker).next().unwrap_or_default()
+        })
+        .trim_end();
+    let tokens: Vec<_> = line.split_whitespace().collect();
+
+    let is_opcode = |token: &&str| dialect.accepts_opcode(token);
+    // opcodes from other dialects are worth pointing out, as they're an easy mistake to make
+    let other_opcode = tokens.iter().take(2).find(|token| {
+        Opcode::MNEMONICS
+            .iter()
+            .any(|opcode| opcode.eq_ignore_ascii_case(token))
+    });
+
+    if tokens.is_empty() {
+        Diagnostic::new("unexpected end of input, expected `}`", Span::of(rest))
+    } else if mid_line {
+        Diagnostic::new(
+            format!(
+                "unexpected `{}`, expected an instruction, macro declaration, macro call or comment",
+                tokens[0]
+            ),
+            Span::of(tokens[0]),
+        )
+    } else if line.starts_with("macro ") {
+        Diagnostic::new("invalid macro declaration", Span::of(line))
+    } else if line.contains("!(") {
+        Diagnostic::new("invalid macro call", Span::of(line))
+    } else if let (false, Some(opcode)) = (tokens.iter().take(2).any(is_opcode), other_opcode) {
+        Diagnostic::new(
+            format!(
+                "opcode `{}` is not available in the {} dialect",
+                opcode,
+                dialect.get_name()
+            ),
+            Span::of(opcode),
+        )
+    } else if !tokens.iter().take(2).any(is_opcode) {
+        // with three tokens, the first one must be a label
+        let opcode = tokens[if tokens.len() >= 3 { 1 } else { 0 }];
+        Diagnostic::new(format!("unknown opcode `{}`", opcode), Span::of(opcode))
+    } else {
+        Diagnostic::new("invalid instruction", Span::of(line))
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::parser::comment;
+    use crate::{diagnostics::SourceMap, parser::comment};
 
     use super::{
+        dialect::Dialect,
         instruction::{Instruction, Opcode},
-        parse_program, Item,
+        parse, parse_program, validate, Item,
     };
 
     #[test]
     fn test_comment_parser() {
         let comment_str = "# a";
-        assert_eq!(comment(comment_str), Ok(("", " a")));
+        assert_eq!(comment(comment_str, Dialect::default()), Ok(("", " a")));
+        assert_eq!(comment("// a", Dialect::higginson()), Ok(("", " a")));
+        assert!(comment("# a", Dialect::higginson()).is_err());
+    }
+
+    #[test]
+    fn test_program_parser_spans() {
+        let mut sources = SourceMap::new();
+        let id = sources.add("test.asm", "IN\n  STO a # store\nA!(b)");
+
+        let program = parse_program(sources.get(id), Dialect::default())
+            .unwrap()
+            .1;
+        let locations: Vec<_> = program
+            .iter()
+            .map(|item| {
+                let location = sources.locate(item.get_span()).unwrap();
+                (location.line, location.column, location.length)
+            })
+            .collect();
+
+        assert_eq!(locations, vec![(1, 1, 2), (2, 3, 5), (2, 9, 7), (3, 1, 5)]);
+    }
+
+    #[test]
+    fn test_program_parser_unparsed() {
+        macro_rules! assert_unparsed {
+            ($($input:expr, $dialect:expr => $message:expr, $fragment:expr;)*) => {
+                $(
+                    let mut sources = SourceMap::new();
+                    let id = sources.add("test.asm", $input);
+
+                    let error = parse(sources.get(id), $dialect).unwrap_err();
+                    assert_eq!(error.get_message(), $message);
+
+                    // check the span covers exactly the offending fragment
+                    let location = sources.locate(error.get_span()).unwrap();
+                    let start = location.column - 1;
+                    assert_eq!(&location.snippet[start..start + location.length], $fragment);
+                )*
+            };
+        }
+
+        assert_unparsed!(
+            "IN\nADDD 10\nOUT", Dialect::default() => "unknown opcode `ADDD`", "ADDD";
+            "IN\nloop ADDD 10", Dialect::default() => "unknown opcode `ADDD`", "ADDD";
+            "IN\nSTO a b", Dialect::default() => "unexpected `b`, expected an instruction, macro declaration, macro call or comment", "b";
+            "macro A() = {\n IN\n FOO\n}", Dialect::default() => "unknown opcode `FOO`", "FOO";
+            "macro A() = {\n IN\n", Dialect::default() => "unexpected end of input, expected `}`", "";
+            "A!(a b)", Dialect::default() => "invalid macro call", "A!(a b)";
+            "IN\nOTC", Dialect::durham() => "opcode `OTC` is not available in the Durham dialect", "OTC";
+            "INP\nx STO y // store", Dialect::higginson() => "opcode `STO` is not available in the Higginson dialect", "STO";
+        );
+
+        assert!(parse("IN\n  # trailing comment\n\n", Dialect::default()).is_ok());
+    }
+
+    #[test]
+    fn test_validate_opcode_labels() {
+        let program = parse("out DAT\nmacro A() = {\n in DAT\n}", Dialect::default()).unwrap();
+
+        assert!(validate(&program, Dialect::durham()).is_empty());
+        assert_eq!(
+            validate(&program, Dialect::madnick())
+                .iter()
+                .map(|error| error.get_message())
+                .collect::<Vec<_>>(),
+            vec![
+                "label `out` has the same name as an opcode, which the Madnick dialect does not allow",
+                "label `in` has the same name as an opcode, which the Madnick dialect does not allow",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_default_arguments() {
+        let program = parse(
+            "macro COPY($src = a, $dst) = {\n STO $dst\n}",
+            Dialect::default(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            validate(&program, Dialect::default())[0].get_message(),
+            "argument `$dst` of macro `COPY` needs a default value, as it comes after `$src` which has one"
+        );
+    }
+
+    #[test]
+    fn test_validate_variadic_arguments() {
+        let program = parse(
+            "macro F($first, $rest...) = {
+                LDA $rest
+                repeat 2 {
+                    ADD $rest+1
+                }
+                for $x in $rest {
+                    F!($x, $rest)
+                }
+            }",
+            Dialect::default(),
+        )
+        .unwrap();
+
+        let errors = validate(&program, Dialect::default());
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].get_message(),
+            "variadic parameter `$rest` can only be used as a `for` list or a macro call argument"
+        );
+    }
+
+    #[test]
+    fn test_keyword_labels() {
+        assert_eq!(
+            parse("global OUT\nglobal done", Dialect::default()),
+            Ok(vec![
+                Item::Instruction(Instruction::new(Some("global"), Opcode::OUT, None)),
+                Item::Global(vec!["done"], Default::default()),
+            ])
+        );
     }
 
     #[test]
@@ -101,7 +524,7 @@ mod test {
         count	DAT	000
         one	DAT	001";
 
-        let parsed = parse_program(preparsed_program);
+        let parsed = parse_program(preparsed_program, Dialect::default());
         assert!(parsed.is_ok());
         let parsed = parsed.unwrap().1;
 
@@ -0,0 +1,171 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use nom::{
+    bytes::complete::tag,
+    character::complete::{multispace0, space1},
+    combinator::{consumed, cut, map, opt, recognize, verify},
+    sequence::{delimited, pair, preceded, tuple},
+    IResult,
+};
+
+use super::{
+    dialect::Dialect,
+    expression::operand,
+    identifier,
+    macros::macro_declaration::{local_labels, substitute_argument_item, Argument},
+    Item,
+};
+use crate::diagnostics::Span;
+
+/// Stores information about a block which is repeated a number of times, such as "repeat 5 { ADD x }"
+#[derive(PartialEq, Debug, Clone)]
+pub struct Repeat<'a> {
+    count: Cow<'a, str>,
+    index: Option<&'a str>,
+    body: Vec<Item<'a>>,
+    span: Span,
+}
+
+impl<'a> Repeat<'a> {
+    /// Creates a new repeat block from the given information
+    pub fn new(count: &'a str, index: Option<&'a str>, body: Vec<Item<'a>>) -> Self {
+        Self {
+            count: Cow::Borrowed(count),
+            index,
+            body,
+            span: Span::default(),
+        }
+    }
+
+    /// Sets the span of source text the repeat block was parsed from
+    pub(crate) fn with_span(self, span: Span) -> Self {
+        Self { span, ..self }
+    }
+
+    /// Creates a new repeat block identical to the current one, but with a different count and body
+    pub(crate) fn clone_with(&self, count: Cow<'a, str>, body: Vec<Item<'a>>) -> Self {
+        Self {
+            count,
+            index: self.index,
+            body,
+            span: self.span,
+        }
+    }
+
+    /// Gets the number of times the body is repeated, which is a number, constant or expression
+    pub fn get_count(&self) -> &str {
+        &self.count
+    }
+
+    /// Gets the parameter which is replaced with the number of the current repetition, starting at 0
+    pub fn get_index(&self) -> Option<&'a str> {
+        self.index
+    }
+
+    /// Gets the repeated body
+    pub fn get_body(&self) -> &Vec<Item<'a>> {
+        &self.body
+    }
+
+    /// Gets the span of source text the repeat block was parsed from
+    pub fn get_span(&self) -> Span {
+        self.span
+    }
+
+    /// Gets the labels defined in the body which are local to each repetition.
+    /// This is every label defined by an instruction in the body, other than parameters and labels declared `global`.
+    pub fn get_local_labels(&self) -> Vec<&str> {
+        local_labels(&self.body)
+    }
+
+    /// Creates a copy of the body for a single repetition, replacing the index parameter with its value
+    /// and renaming any labels in the given map.
+    pub(crate) fn substitute(
+        &self,
+        index: usize,
+        renames: &HashMap<&str, String>,
+    ) -> Vec<Item<'a>> {
+        let mut map: HashMap<_, _> = renames
+            .iter()
+            .map(|(&old, new)| (old, Argument::Value(Cow::Owned(new.clone()))))
+            .collect();
+        if let Some(parameter) = self.index {
+            map.insert(parameter, Argument::Value(Cow::Owned(index.to_string())));
+        }
+
+        self.body
+            .iter()
+            .map(|item| substitute_argument_item(item, &map))
+            .collect()
+    }
+}
+
+/// Matches a repeat block, such as "repeat 5 { ADD x }" or "repeat LIMIT as $i { DAT $i }"
+pub(crate) fn repeat(input: &str, dialect: Dialect) -> IResult<&str, Repeat<'_>> {
+    map(
+        consumed(tuple((
+            // matches the count
+            preceded(
+                pair(tag("repeat"), space1),
+                verify(operand, |count: &str| !count.is_empty()),
+            ),
+            // matches the optional index parameter
+            opt(preceded(
+                tuple((space1, tag("as"), space1)),
+                recognize(pair(tag("$"), identifier)),
+            )),
+            // matches the body, which must be closed once it has been opened
+            delimited(
+                pair(multispace0, tag("{")),
+                |input| super::parse_program(input, dialect),
+                cut(pair(multispace0, tag("}"))),
+            ),
+        ))),
+        |(fragment, (count, index, body))| {
+            Repeat::new(count, index, body).with_span(Span::of(fragment))
+        },
+    )(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser::instruction::{Instruction, Opcode};
+
+    #[test]
+    fn test_repeat_parsing() {
+        let parsed = repeat(
+            "repeat N*2 as $i {
+                loop DAT $i
+            }",
+            Dialect::default(),
+        )
+        .unwrap()
+        .1;
+
+        assert_eq!(
+            parsed,
+            Repeat::new(
+                "N*2",
+                Some("$i"),
+                vec![Item::Instruction(Instruction::new(
+                    Some("loop"),
+                    Opcode::DAT,
+                    Some("$i")
+                ))]
+            )
+        );
+
+        let renames = vec![("loop", "loop_3".to_string())].into_iter().collect();
+        assert_eq!(
+            parsed.substitute(2, &renames),
+            vec![Item::Instruction(Instruction::new(
+                Some("loop_3"),
+                Opcode::DAT,
+                Some("2")
+            ))]
+        );
+
+        assert!(repeat("repeat DAT", Dialect::default()).is_err());
+    }
+}
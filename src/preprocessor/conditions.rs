@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+
+use crate::{
+    diagnostics::{Diagnostic, Span},
+    parser::{directive::Condition, Item},
+};
+
+/// An `if` directive which hasn't been closed by an `endif` yet
+struct Branch {
+    span: Span,
+    /// Whether the enclosing branch is being kept
+    parent_active: bool,
+    /// Whether the current side of the branch is selected, which flips at `else`
+    selected: bool,
+    else_span: Option<Span>,
+}
+
+impl Branch {
+    /// Returns true if items in the current side of the branch should be kept
+    fn is_active(&self) -> bool {
+        self.parent_active && self.selected
+    }
+}
+
+/// Evaluates conditional directives, keeping only the items in branches whose condition holds.
+/// Symbols set by `define` apply to everything after them, including the bodies of macros declared later.
+pub(crate) fn evaluate<'a>(
+    program: &[Item<'a>],
+    symbols: &mut HashMap<String, String>,
+    errors: &mut Vec<Diagnostic>,
+) -> Vec<Item<'a>> {
+    let mut output = Vec::new();
+    let mut branches: Vec<Branch> = Vec::new();
+
+    for item in program {
+        let active = branches.last().is_none_or(Branch::is_active);
+
+        match item {
+            Item::If(condition, span) => {
+                // conditions in branches which aren't kept are never tested, so can't cause errors
+                let selected = active && test(condition, *span, symbols, errors);
+                branches.push(Branch {
+                    span: *span,
+                    parent_active: active,
+                    selected,
+                    else_span: None,
+                });
+            }
+            Item::Else(span) => match branches.last_mut() {
+                Some(Branch {
+                    else_span: Some(first),
+                    ..
+                }) => errors.push(
+                    Diagnostic::new("`if` already has an `else`", *span)
+                        .with_note("first `else` here", *first),
+                ),
+                Some(branch) => {
+                    branch.selected = !branch.selected;
+                    branch.else_span = Some(*span);
+                }
+                None => errors.push(Diagnostic::new("`else` without a matching `if`", *span)),
+            },
+            Item::EndIf(span) => {
+                if branches.pop().is_none() {
+                    errors.push(Diagnostic::new("`endif` without a matching `if`", *span));
+                }
+            }
+            _ if !active => {}
+            Item::Define(name, value, _) => {
+                symbols.insert(name.to_string(), value.unwrap_or("1").to_string());
+            }
+            Item::MacroDeclaration(declaration) => {
+                let body = evaluate(declaration.get_body(), symbols, errors);
+                output.push(Item::MacroDeclaration(declaration.clone_with_body(body)));
+            }
+            Item::Repeat(repeat) => {
+                let body = evaluate(repeat.get_body(), symbols, errors);
+                output.push(Item::Repeat(
+                    repeat.clone_with(repeat.get_count().to_string().into(), body),
+                ));
+            }
+            Item::ForEach(for_each) => {
+                let body = evaluate(for_each.get_body(), symbols, errors);
+                output.push(Item::ForEach(
+                    for_each.clone_with(for_each.get_values().clone(), body),
+                ));
+            }
+            _ => output.push(item.clone()),
+        }
+    }
+
+    for branch in branches {
+        errors.push(Diagnostic::new(
+            "`if` without a matching `endif`",
+            branch.span,
+        ));
+    }
+
+    output
+}
+
+/// Tests whether a condition holds, reporting an error if it compares a symbol which hasn't been defined
+fn test(
+    condition: &Condition,
+    span: Span,
+    symbols: &HashMap<String, String>,
+    errors: &mut Vec<Diagnostic>,
+) -> bool {
+    let (name, expected, equal) = match condition {
+        Condition::Defined(name) => return symbols.contains_key(*name),
+        Condition::NotDefined(name) => return !symbols.contains_key(*name),
+        Condition::Set(name) => return symbols.get(*name).is_some_and(|value| value != "0"),
+        Condition::Equals(name, expected) => (name, expected, true),
+        Condition::NotEquals(name, expected) => (name, expected, false),
+    };
+
+    match symbols.get(*name) {
+        Some(value) => (value == expected) == equal,
+        None => {
+            errors.push(Diagnostic::new(
+                format!(
+                    "symbol `{}` is not defined, use `ifdef` to check whether it is",
+                    name
+                ),
+                span,
+            ));
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse;
+
+    /// Evaluates the program with the given symbols defined, rendering what's left
+    fn evaluate_with(program: &str, symbols: &[(&str, &str)]) -> Result<String, Vec<String>> {
+        let program = parse(program).unwrap();
+        let mut symbols = symbols
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let mut errors = Vec::new();
+
+        let output = evaluate(&program, &mut symbols, &mut errors);
+        if errors.is_empty() {
+            Ok(crate::render(&output, Default::default()))
+        } else {
+            Err(errors
+                .iter()
+                .map(|error| error.get_message().to_string())
+                .collect())
+        }
+    }
+
+    #[test]
+    fn test_conditions() {
+        let program = "ifdef DEBUG
+                OUT
+            else
+                IN
+            endif
+            if LEVEL == 2
+                ifndef DEBUG
+                    ADD one
+                endif
+                SUB one
+            endif
+            HLT";
+
+        assert_eq!(
+            evaluate_with(program, &[("LEVEL", "1")]),
+            Ok("\tIN\n\tHLT\n".to_string())
+        );
+        assert_eq!(
+            evaluate_with(program, &[("LEVEL", "2")]),
+            Ok("\tIN\n\tADD\tone\n\tSUB\tone\n\tHLT\n".to_string())
+        );
+        assert_eq!(
+            evaluate_with(program, &[("LEVEL", "2"), ("DEBUG", "1")]),
+            Ok("\tOUT\n\tSUB\tone\n\tHLT\n".to_string())
+        );
+    }
+
+    #[test]
+    fn test_define() {
+        let program = "define TRACE
+            if TRACE
+                OUT
+            endif
+            define TRACE = 0
+            macro A() = {
+                if TRACE
+                    OUT
+                else
+                    HLT
+                endif
+            }";
+
+        // the macro body sees the value the symbol had when the macro was declared
+        let output = parse(program).unwrap();
+        let mut errors = Vec::new();
+        let output = evaluate(&output, &mut HashMap::new(), &mut errors);
+
+        assert!(errors.is_empty());
+        assert_eq!(crate::render(&output, Default::default()), "\tOUT\n\n");
+        match &output[1] {
+            Item::MacroDeclaration(declaration) => {
+                assert_eq!(
+                    crate::render(declaration.get_body(), Default::default()),
+                    "\tHLT\n"
+                )
+            }
+            item => panic!("expected a macro declaration, found {:?}", item),
+        }
+    }
+
+    #[test]
+    fn test_condition_errors() {
+        assert_eq!(
+            evaluate_with("else\nendif", &[]),
+            Err(vec![
+                "`else` without a matching `if`".to_string(),
+                "`endif` without a matching `if`".to_string()
+            ])
+        );
+        assert_eq!(
+            evaluate_with("ifdef A\nelse\nelse\nendif\nif B", &[]),
+            Err(vec![
+                "`if` already has an `else`".to_string(),
+                "`if` without a matching `endif`".to_string()
+            ])
+        );
+        assert_eq!(
+            evaluate_with("if MODE == fast\nendif", &[]),
+            Err(vec![
+                "symbol `MODE` is not defined, use `ifdef` to check whether it is".to_string()
+            ])
+        );
+    }
+}
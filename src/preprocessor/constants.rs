@@ -0,0 +1,115 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use crate::{
+    diagnostics::{Diagnostic, Span},
+    parser::{expression::substitute_names, Item},
+};
+
+/// Replaces every operand naming a constant with the constant's value, removing the constant declarations.
+/// Constants can be used before they are declared, and can be declared more than once as long as the value is the same,
+/// which happens when a macro declaring one is called more than once.
+pub(crate) fn substitute<'a>(program: &[Item<'a>]) -> Result<Vec<Item<'a>>, Vec<Diagnostic>> {
+    let mut errors = Vec::new();
+
+    let mut constants: HashMap<&str, (&'a str, Span)> = HashMap::new();
+    for item in program {
+        if let Item::Constant(name, value, span) = item {
+            match constants.get(name) {
+                Some((first, first_span)) if first != value => errors.push(
+                    Diagnostic::new(
+                        format!("constant `{}` is defined more than once", name),
+                        *span,
+                    )
+                    .with_note(format!("first defined as {} here", first), *first_span),
+                ),
+                Some(_) => {}
+                None => {
+                    constants.insert(name, (value, *span));
+                }
+            }
+        }
+    }
+
+    // a name that is both a label and a constant would be ambiguous as an operand
+    for item in program {
+        if let Item::Instruction(instruction) = item {
+            let constant = instruction
+                .get_label()
+                .and_then(|label| constants.get(label).map(|constant| (label, constant)));
+            if let Some((label, (_, span))) = constant {
+                errors.push(
+                    Diagnostic::new(
+                        format!("`{}` is defined as both a label and a constant", label),
+                        instruction.get_span(),
+                    )
+                    .with_note("constant defined here", *span),
+                );
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    Ok(program
+        .iter()
+        .filter_map(|item| match item {
+            Item::Instruction(instruction) => {
+                let operand = instruction.get_operand().and_then(|operand| {
+                    substitute_names(operand, |name| {
+                        constants.get(name).map(|(value, _)| Cow::Borrowed(*value))
+                    })
+                });
+                Some(Item::Instruction(match operand {
+                    Some(operand) => instruction.clone_with_resolved_operand(operand),
+                    None => instruction.clone(),
+                }))
+            }
+            Item::Constant(..) => None,
+            _ => Some(item.clone()),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn test_constants() {
+        let program = parse(
+            "LDA LIMIT
+            ADD LIMIT*2-one
+            const LIMIT = 25
+            DAT LIMIT
+            const LIMIT = 25
+            one DAT 1",
+        )
+        .unwrap();
+
+        assert_eq!(
+            crate::render(&substitute(&program).unwrap(), Default::default()),
+            "\tLDA\t25\n\tADD\t25*2-one\n\tDAT\t25\none\tDAT\t1\n"
+        );
+    }
+
+    #[test]
+    fn test_constant_errors() {
+        let program = parse("const A = 1\nconst A = 2\nB DAT\nconst B = 3").unwrap();
+        let errors: Vec<_> = substitute(&program)
+            .unwrap_err()
+            .iter()
+            .map(|error| error.get_message().to_string())
+            .collect();
+
+        assert_eq!(
+            errors,
+            vec![
+                "constant `A` is defined more than once",
+                "`B` is defined as both a label and a constant"
+            ]
+        );
+    }
+}
@@ -1,29 +1,271 @@
-use crate::parser::{macros::macro_declaration::MacroDeclaration, Item};
+mod conditions;
+mod constants;
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use crate::{
+    diagnostics::{Diagnostic, Span},
+    parser::{
+        dialect::Dialect,
+        expression::evaluate,
+        for_each::ForEach,
+        instruction::{Instruction, Opcode, Operation},
+        macros::{macro_call::MacroCall, macro_declaration::MacroDeclaration},
+        repeat::Repeat,
+        Item,
+    },
+};
+
+/// Options controlling how macros are expanded
+#[derive(Debug, Clone)]
+pub struct ExpandOptions {
+    /// If true, calls to macros without a declaration are replaced with nothing instead of being an error
+    pub allow_undefined_macros: bool,
+    /// The maximum number of macro calls that can be nested inside each other
+    pub max_expansion_depth: usize,
+    /// The maximum number of items the expanded program can have, which stops repeat blocks from growing without end
+    pub max_expanded_items: usize,
+    /// Symbols which are defined before the program starts, as if by `define NAME = value`
+    pub symbols: HashMap<String, String>,
+    /// The dialect the program is written in, which decides the opcodes that can be given to opcode parameters
+    pub dialect: Dialect,
+}
+
+impl Default for ExpandOptions {
+    fn default() -> Self {
+        Self {
+            allow_undefined_macros: false,
+            max_expansion_depth: 64,
+            max_expanded_items: 100_000,
+            symbols: HashMap::new(),
+            dialect: Dialect::default(),
+        }
+    }
+}
 
 /// Goes through the program, creating a new one with all macro invocations replaced with the given macro body.
-/// If a macro does not have a declaration, it is simply ignored and replaced with nothing.
-pub(crate) fn replace_macro<'a, 'b>(program: &'a [Item<'b>]) -> Vec<Item<'b>> {
-    /// Replaces all macro calls with the definition once, may need to be ran multiple times.
-    fn replace_once<'a, 'b>(
-        program: &'a [Item<'b>],
-        macros: &[&MacroDeclaration<'b>],
-    ) -> Vec<Item<'b>> {
+/// If a macro does not have a declaration, an error is reported unless undefined macros are allowed,
+/// in which case the call is simply replaced with nothing.
+/// Conditional directives are evaluated first, so only the selected branches are expanded,
+/// and constants are substituted last, so they can be used in the arguments of macro calls.
+pub(crate) fn replace_macro<'b>(
+    program: &[Item<'b>],
+    options: &ExpandOptions,
+) -> Result<Vec<Item<'b>>, Vec<Diagnostic>> {
+    let mut errors = Vec::new();
+    let program = &conditions::evaluate(program, &mut options.symbols.clone(), &mut errors);
+
+    // labels can only be made global from inside something that would otherwise rename them
+    for item in program {
+        if let Item::Global(_, span) = item {
+            errors.push(Diagnostic::new(
+                "`global` can only be used inside a macro, repeat or for block",
+                *span,
+            ));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // initially need to find all macro definitions,
+    // where a file reached more than once gives the same declaration each time, so it is only kept once
+    let mut macros: Vec<&MacroDeclaration> = Vec::new();
+    for item in program {
+        if let Item::MacroDeclaration(declaration) = item {
+            let span = declaration.get_span();
+            if span.is_empty() || !macros.iter().any(|other| other.get_span().same_as(&span)) {
+                macros.push(declaration);
+            }
+        }
+    }
+
+    // macros can share a name as long as they take different numbers of arguments,
+    // otherwise calls to them would be ambiguous
+    for (index, declaration) in macros.iter().enumerate() {
+        let arity = declaration.get_arity();
+        let first = macros[..index].iter().find_map(|other| {
+            let other_arity = other.get_arity();
+            let overlap = *arity.start().max(other_arity.start());
+            (other.get_identifier() == declaration.get_identifier()
+                && overlap <= *arity.end().min(other_arity.end()))
+            .then_some((other, overlap))
+        });
+        if let Some((first, count)) = first {
+            errors.push(ambiguous_macro(declaration, first, count));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    // labels generated for each expansion need to be different to every existing label
+    let mut labels = HashSet::new();
+    collect_labels(program, &mut labels);
+
+    // then recursively replace each macro call with the macro definition body
+    // repeat counts can use constants declared at the top level of the program,
+    // along with those declared by macros as they are expanded
+    let constants = program
+        .iter()
+        .filter_map(|item| match item {
+            Item::Constant(name, value, _) => Some((*name, value.parse().unwrap_or(i64::MAX))),
+            _ => None,
+        })
+        .collect();
+
+    let mut expander = Expander {
+        macros,
+        options,
+        constants,
+        labels,
+        data: Vec::new(),
+        items: 0,
+        call: Span::default(),
+        too_many_items_reported: false,
+        errors: Vec::new(),
+    };
+    let mut output = expander.expand(program, &mut Vec::new());
+
+    // local storage goes after everything else, so it is never run as an instruction
+    output.append(&mut expander.data);
+
+    if expander.errors.is_empty() {
+        constants::substitute(&output)
+    } else {
+        Err(expander.errors)
+    }
+}
+
+/// Stores the state needed while expanding macro calls
+struct Expander<'m, 'b> {
+    macros: Vec<&'m MacroDeclaration<'b>>,
+    options: &'m ExpandOptions,
+    constants: HashMap<&'b str, i64>,
+    labels: HashSet<String>,
+    /// The storage declared with `local` by each expansion, in the order it was declared
+    data: Vec<Item<'b>>,
+    /// The number of items output so far, including storage
+    items: usize,
+    /// The span of the outermost macro call currently being expanded
+    call: Span,
+    too_many_items_reported: bool,
+    errors: Vec<Diagnostic>,
+}
+
+impl<'m, 'b> Expander<'m, 'b> {
+    /// Expands every item in the program, where chain is the name and number of arguments
+    /// of each macro currently being expanded
+    fn expand(&mut self, program: &[Item<'b>], chain: &mut Vec<(&'b str, usize)>) -> Vec<Item<'b>> {
         program
             .iter()
-            .flat_map(|item| match item.clone() {
-                // simply move instructions over, no changes required
-                item @ Item::Instruction(_) | item @ Item::Comment(_) => vec![item],
-                Item::MacroCall(call) => {
-                    // find the corresponding macro definition
-                    let macro_definition = macros
-                        .iter()
-                        .find(|macro_call| macro_call.get_identifier() == call.get_identifier());
-
-                    // if a definition exists, substitute the arguments with the new ones
-                    // if a definition does not exist, or substituting arguments fails, simply return an empty vector (outputting nothing)
-                    macro_definition
-                        .and_then(|x| x.substitute_arguments(call.get_arguments()))
-                        .unwrap_or_else(Vec::new)
+            .flat_map(|item| match item {
+                // parameters used as opcodes should have been replaced by now
+                Item::Instruction(instruction) => match instruction.get_operation() {
+                    Operation::Parameter(parameter) if parameter.starts_with('$') => {
+                        self.report(Diagnostic::new(
+                            format!("`{}` is not a valid opcode", parameter),
+                            instruction.get_span(),
+                        ));
+                        Vec::new()
+                    }
+                    // arguments given to opcode parameters are written as the caller wrote them
+                    Operation::Parameter(argument) => {
+                        match opcode_argument(instruction, argument, self.options.dialect) {
+                            Ok(opcode) => {
+                                self.items += 1;
+                                vec![Item::Instruction(
+                                    instruction.clone_with_operation(Operation::Opcode(opcode)),
+                                )]
+                            }
+                            Err(message) => {
+                                self.report(Diagnostic::new(message, instruction.get_span()));
+                                Vec::new()
+                            }
+                        }
+                    }
+                    // the standard library can be used from any dialect, so its opcodes are checked once expanded
+                    Operation::Opcode(opcode) if !self.options.dialect.has_opcode(opcode) => {
+                        self.report(
+                            Diagnostic::new(
+                                format!(
+                                    "opcode `{}` is not available in the {} dialect",
+                                    opcode,
+                                    self.options.dialect.get_name()
+                                ),
+                                instruction.get_span(),
+                            )
+                            .with_note("used by the macro called here", self.call),
+                        );
+                        Vec::new()
+                    }
+                    // simply move instructions over, no changes required
+                    Operation::Opcode(_) => {
+                        self.items += 1;
+                        vec![item.clone()]
+                    }
+                },
+                // constants are kept until everything is expanded, as macros can declare them too,
+                // but are noted straight away so later repeat counts can use them
+                Item::Constant(name, value, _) => {
+                    self.constants
+                        .entry(name)
+                        .or_insert_with(|| value.parse().unwrap_or(i64::MAX));
+                    self.items += 1;
+                    vec![item.clone()]
+                }
+                // sections are kept for the layout pass
+                Item::Comment(..) | Item::Section(..) => {
+                    self.items += 1;
+                    vec![item.clone()]
+                }
+                Item::MacroCall(call) => self.expand_call(call, chain),
+                Item::Repeat(repeat) => self.expand_repeat(repeat, chain),
+                Item::ForEach(for_each) => self.expand_for_each(for_each, chain),
+                Item::Local(name, value, span) => {
+                    // names are only replaced inside a macro, repeat or for block
+                    if name.starts_with('$') {
+                        self.report(Diagnostic::new(
+                            format!(
+                                "`local {}` can only be declared inside a macro, repeat or for block",
+                                name
+                            ),
+                            *span,
+                        ));
+                    } else {
+                        let mut storage = Instruction::new(None, Opcode::DAT, None)
+                            .clone_with_label(name.clone())
+                            .with_span(*span);
+                        if let Some(value) = value {
+                            storage = storage.clone_with_operand(value.clone());
+                        }
+                        self.items += 1;
+                        self.data.push(Item::Instruction(storage));
+                    }
+                    Vec::new()
+                }
+                Item::Use(module, span) => {
+                    self.report(Diagnostic::new(
+                        format!(
+                            "`{}` was not loaded, as `use` only works at the top level of a loaded file",
+                            module
+                        ),
+                        *span,
+                    ));
+                    Vec::new()
+                }
+                Item::Include(path, span) => {
+                    self.report(Diagnostic::new(
+                        format!(
+                            "`{}` was not included, as `include` only works at the top level of a loaded file",
+                            path
+                        ),
+                        *span,
+                    ));
+                    Vec::new()
                 }
                 // everything else is discarded
                 _ => Vec::new(),
@@ -31,25 +273,976 @@ pub(crate) fn replace_macro<'a, 'b>(program: &'a [Item<'b>]) -> Vec<Item<'b>> {
             .collect()
     }
 
-    // initially need to find all macro definitions
-    let macros: Vec<_> = program
-        .iter()
-        .filter_map(|item| match item {
-            Item::MacroDeclaration(macro_def) => Some(macro_def),
+    /// Expands a single macro call, including any macro calls in its body.
+    /// If the call can't be expanded, an error is reported and nothing is output.
+    fn expand_call(
+        &mut self,
+        call: &MacroCall<'b>,
+        chain: &mut Vec<(&'b str, usize)>,
+    ) -> Vec<Item<'b>> {
+        if chain.is_empty() {
+            self.call = call.get_span();
+        }
+
+        // calls which each make several more calls can grow as quickly as nested repeat blocks
+        if self.too_many_items(call.get_span()) {
+            return Vec::new();
+        }
+
+        // find the corresponding macro definition, choosing between overloads by the number of arguments
+        let overloads: Vec<_> = self
+            .macros
+            .iter()
+            .copied()
+            .filter(|declaration| declaration.get_identifier() == call.get_identifier())
+            .collect();
+        let macro_definition = overloads.iter().copied().find(|declaration| {
+            declaration
+                .get_arity()
+                .contains(&call.get_arguments().len())
+        });
+
+        let error = match macro_definition {
+            Some(definition) if chain.contains(&overload_key(definition)) => {
+                Some(recursive_macro(call, chain, "recursive macro expansion"))
+            }
+            _ if chain.len() >= self.options.max_expansion_depth => Some(recursive_macro(
+                call,
+                chain,
+                &format!(
+                    "macro expansion exceeded the maximum depth of {}",
+                    self.options.max_expansion_depth
+                ),
+            )),
+            None if !overloads.is_empty() => Some(arity_mismatch(call, &overloads)),
+            None if !self.options.allow_undefined_macros => {
+                Some(undefined_macro(call, &self.macros))
+            }
             _ => None,
-        })
+        };
+
+        if let Some(error) = error {
+            self.report(error);
+            return Vec::new();
+        }
+
+        // if a definition does not exist, simply return an empty vector (outputting nothing)
+        let definition = match macro_definition {
+            Some(definition) => definition,
+            None => return Vec::new(),
+        };
+
+        // otherwise give each local label a new name, so labels from different expansions don't clash
+        let renames: HashMap<_, _> = definition
+            .get_local_labels()
+            .into_iter()
+            .map(|label| (label, self.fresh_label(label)))
+            .collect();
+
+        // then substitute the arguments with the new ones and expand any calls in the body
+        match definition.substitute(call.get_arguments(), &renames) {
+            Some(body) => {
+                // arguments given to opcode parameters are reported at the call, as that's where they were written
+                let invalid_opcodes: Vec<_> = body
+                    .iter()
+                    .filter_map(|item| match item {
+                        Item::Instruction(instruction) => match instruction.get_operation() {
+                            Operation::Parameter(argument) if !argument.starts_with('$') => {
+                                opcode_argument(instruction, argument, self.options.dialect).err()
+                            }
+                            _ => None,
+                        }
+                        .map(|message| {
+                            Diagnostic::new(message, call.get_span())
+                                .with_note("used as an opcode here", instruction.get_span())
+                        }),
+                        _ => None,
+                    })
+                    .collect();
+
+                if !invalid_opcodes.is_empty() {
+                    invalid_opcodes
+                        .into_iter()
+                        .for_each(|error| self.report(error));
+                    return Vec::new();
+                }
+
+                chain.push(overload_key(definition));
+                let output = self.expand(&body, chain);
+                chain.pop();
+
+                if self.too_many_items(call.get_span()) {
+                    return Vec::new();
+                }
+                output
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Expands a repeat block, giving each repetition its own copy of any labels in the body.
+    /// If the count can't be worked out, an error is reported and nothing is output.
+    fn expand_repeat(
+        &mut self,
+        repeat: &Repeat<'b>,
+        chain: &mut Vec<(&'b str, usize)>,
+    ) -> Vec<Item<'b>> {
+        // counts are worked out before labels have addresses, so can only use constants
+        let count = evaluate(repeat.get_count(), |name| {
+            self.constants.get(name).copied().ok_or_else(|| {
+                format!(
+                    "undefined constant `{}`, repeat counts can only use numbers and constants",
+                    name
+                )
+            })
+        });
+
+        let count = match count {
+            Ok(count) if (0..=999).contains(&count) => count as usize,
+            Ok(count) => {
+                self.report(Diagnostic::new(
+                    format!(
+                        "repeat count `{}` evaluates to {}, expected a value from 0 to 999",
+                        repeat.get_count(),
+                        count
+                    ),
+                    repeat.get_span(),
+                ));
+                return Vec::new();
+            }
+            Err(message) => {
+                self.report(Diagnostic::new(message, repeat.get_span()));
+                return Vec::new();
+            }
+        };
+
+        let mut output = Vec::new();
+        for index in 0..count {
+            let renames: HashMap<_, _> = repeat
+                .get_local_labels()
+                .into_iter()
+                .map(|label| (label, self.fresh_label(label)))
+                .collect();
+
+            let body = repeat.substitute(index, &renames);
+            output.extend(self.expand(&body, chain));
+            if self.too_many_items(repeat.get_span()) {
+                break;
+            }
+        }
+
+        output
+    }
+
+    /// Expands a for each block, giving each repetition its own copy of any labels in the body
+    fn expand_for_each(
+        &mut self,
+        for_each: &ForEach<'b>,
+        chain: &mut Vec<(&'b str, usize)>,
+    ) -> Vec<Item<'b>> {
+        let mut output = Vec::new();
+        for value in for_each.get_values() {
+            let renames: HashMap<_, _> = for_each
+                .get_local_labels()
+                .into_iter()
+                .map(|label| (label, self.fresh_label(label)))
+                .collect();
+
+            let body = for_each.substitute(value, &renames);
+            output.extend(self.expand(&body, chain));
+            if self.too_many_items(for_each.get_span()) {
+                break;
+            }
+        }
+
+        output
+    }
+
+    /// Returns true if the expanded program has more items than the limit allows,
+    /// reporting an error at the given span the first time the limit is passed
+    fn too_many_items(&mut self, span: Span) -> bool {
+        let limit = self.options.max_expanded_items;
+        if self.items <= limit {
+            return false;
+        }
+
+        // only the innermost block is reported, as every block around it is stopped too
+        if !self.too_many_items_reported {
+            self.too_many_items_reported = true;
+            self.report(Diagnostic::new(
+                format!(
+                    "expansion produced more than the maximum of {} items",
+                    limit
+                ),
+                span,
+            ));
+        }
+
+        true
+    }
+
+    /// Reports an error, ignoring it if the same error has already been reported.
+    /// Calls within a macro body are seen once per expansion, so would otherwise be reported many times.
+    fn report(&mut self, error: Diagnostic) {
+        if !self.errors.iter().any(|existing| existing.same_as(&error)) {
+            self.errors.push(error);
+        }
+    }
+
+    /// Creates a label based on the given one which isn't used anywhere else in the program,
+    /// such as "loop_1" for "loop" or "tmp_1" for "$tmp"
+    fn fresh_label(&mut self, label: &str) -> String {
+        let label = label.trim_start_matches('$');
+        let label = (1..)
+            .map(|n| format!("{}_{}", label, n))
+            .find(|candidate| !self.labels.contains(candidate))
+            .unwrap_or_default();
+
+        self.labels.insert(label.clone());
+        label
+    }
+}
+
+/// Gets the opcode for an argument given to an opcode parameter, which must be an opcode the dialect accepts
+/// and can't have an operand if the opcode doesn't take one
+fn opcode_argument(
+    instruction: &Instruction,
+    argument: &str,
+    dialect: Dialect,
+) -> Result<Opcode, String> {
+    let opcode = match Opcode::from_str(&argument.to_ascii_uppercase()) {
+        Ok(opcode) if dialect.accepts_opcode(argument) => opcode,
+        Ok(_) => {
+            return Err(format!(
+                "opcode `{}` is not available in the {} dialect",
+                argument,
+                dialect.get_name()
+            ))
+        }
+        Err(_) => return Err(format!("`{}` is not a valid opcode", argument)),
+    };
+
+    match instruction.get_operand() {
+        Some(_) if !opcode.takes_operand() => Err(format!("`{}` does not take an operand", opcode)),
+        _ => Ok(opcode),
+    }
+}
+
+/// Collects every label defined in the program, including inside macro bodies,
+/// along with the names of constants and local storage as they share the same names
+fn collect_labels(program: &[Item], labels: &mut HashSet<String>) {
+    for item in program {
+        match item {
+            Item::Instruction(instruction) => {
+                labels.extend(instruction.get_label().map(str::to_string));
+            }
+            Item::Constant(name, _, _) => {
+                labels.insert(name.to_string());
+            }
+            Item::Local(name, _, _) if !name.starts_with('$') => {
+                labels.insert(name.to_string());
+            }
+            Item::MacroDeclaration(declaration) => collect_labels(declaration.get_body(), labels),
+            Item::Repeat(repeat) => collect_labels(repeat.get_body(), labels),
+            Item::ForEach(for_each) => collect_labels(for_each.get_body(), labels),
+            _ => {}
+        }
+    }
+}
+
+/// Creates an error for a call that can't be expanded because of the macros it is nested inside,
+/// showing the full chain of macro calls that led to it
+fn recursive_macro(call: &MacroCall, chain: &[(&str, usize)], message: &str) -> Diagnostic {
+    let chain: Vec<_> = chain
+        .iter()
+        .map(|(identifier, _)| *identifier)
+        .chain(std::iter::once(call.get_identifier()))
         .collect();
 
-    // then replace each macro call with the macro definition body
-    let mut output: Vec<_> = replace_once(program, &macros);
+    Diagnostic::new(
+        format!("{}: {}", message, chain.join(" → ")),
+        call.get_span(),
+    )
+}
+
+/// Creates an error for a call to a macro with no declaration, suggesting the closest declared name if there is one
+fn undefined_macro(call: &MacroCall, macros: &[&MacroDeclaration]) -> Diagnostic {
+    let error = Diagnostic::new(
+        format!("call to undefined macro `{}`", call.get_identifier()),
+        call.get_span(),
+    );
+
+    // only suggest names that are reasonably close, otherwise the suggestion is just noise
+    let max_distance = (call.get_identifier().len() / 3).max(2);
+    let closest = macros
+        .iter()
+        .map(|declaration| {
+            let distance = strsim::levenshtein(call.get_identifier(), declaration.get_identifier());
+            (distance, declaration)
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance);
+
+    match closest {
+        Some((_, declaration)) => error.with_note(
+            format!("did you mean `{}`?", declaration.get_identifier()),
+            declaration.get_span(),
+        ),
+        None => error,
+    }
+}
+
+/// Gets the name and number of arguments of a macro, which together identify one overload of it
+fn overload_key<'b>(declaration: &MacroDeclaration<'b>) -> (&'b str, usize) {
+    (
+        declaration.get_identifier(),
+        declaration.get_arguments().len(),
+    )
+}
+
+/// Creates an error for a macro declared with the same name as an earlier one,
+/// where both can be called with the given number of arguments
+fn ambiguous_macro(
+    declaration: &MacroDeclaration,
+    first: &MacroDeclaration,
+    count: usize,
+) -> Diagnostic {
+    Diagnostic::new(
+        format!(
+            "macro `{}` is already declared with {} argument{}",
+            declaration.get_identifier(),
+            count,
+            if count == 1 { "" } else { "s" }
+        ),
+        declaration.get_span(),
+    )
+    .with_note("first declared here", first.get_span())
+}
 
-    // if the output still contains any macro calls, need to repeat
-    while output
+/// Creates an error for a call which supplies a number of arguments that no overload of the macro takes,
+/// with a note showing each declaration
+fn arity_mismatch(call: &MacroCall, overloads: &[&MacroDeclaration]) -> Diagnostic {
+    // variadic macros take any number of arguments from their minimum upwards
+    let minimum = overloads
+        .iter()
+        .filter(|declaration| declaration.is_variadic())
+        .map(|declaration| *declaration.get_arity().start())
+        .min();
+    let mut counts: Vec<_> = overloads
         .iter()
-        .any(|item| matches!(item, Item::MacroCall(..)))
-    {
-        output = replace_once(&output, &macros);
+        .filter(|declaration| !declaration.is_variadic())
+        .flat_map(|declaration| declaration.get_arity())
+        .filter(|&count| minimum.is_none_or(|minimum| count < minimum))
+        .collect();
+    counts.sort_unstable();
+    counts.dedup();
+
+    // lists the counts in words, such as "1, 2 or 3" or "0 or at least 2"
+    let mut expected: Vec<_> = counts.iter().map(usize::to_string).collect();
+    expected.extend(minimum.map(|minimum| format!("at least {}", minimum)));
+    let expected = match expected.split_last() {
+        Some((last, [])) => last.to_string(),
+        Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+        None => String::new(),
+    };
+    let plural = minimum.or(counts.last().copied()) != Some(1);
+    let supplied = call.get_arguments().len();
+
+    let mut error = Diagnostic::new(
+        format!(
+            "macro `{}` takes {} argument{} but {} {} supplied",
+            call.get_identifier(),
+            expected,
+            if plural { "s" } else { "" },
+            supplied,
+            if supplied == 1 { "was" } else { "were" }
+        ),
+        call.get_span(),
+    );
+    for declaration in overloads {
+        let arguments: Vec<_> = declaration
+            .get_arguments()
+            .iter()
+            .zip(declaration.get_defaults())
+            .map(|(argument, default)| match default {
+                Some(default) => format!("{} = {}", argument, default),
+                None => argument.to_string(),
+            })
+            .collect();
+        let variadic = if declaration.is_variadic() { "..." } else { "" };
+
+        error = error.with_note(
+            format!(
+                "`{}` is declared as `{}({}{})`",
+                declaration.get_identifier(),
+                declaration.get_identifier(),
+                arguments.join(", "),
+                variadic
+            ),
+            declaration.get_span(),
+        );
     }
 
-    output
+    error
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parse;
+
+    #[test]
+    fn test_undefined_macro() {
+        let program = parse(
+            "macro IN_STO($a) = {
+                IN
+                STO $a
+            }
+            IN_ST0!(a)
+            IN_STO!(b)
+            FOO!(c)",
+        )
+        .unwrap();
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.get_message())
+                .collect::<Vec<_>>(),
+            vec![
+                "call to undefined macro `IN_ST0`",
+                "call to undefined macro `FOO`"
+            ]
+        );
+        assert!(errors[0]
+            .render(&Default::default())
+            .contains("did you mean `IN_STO`?"));
+
+        let options = ExpandOptions {
+            allow_undefined_macros: true,
+            ..Default::default()
+        };
+        assert_eq!(replace_macro(&program, &options).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_arity_mismatch() {
+        let program = parse(
+            "macro ADD_TWO($a, $b) = {
+                ADD $a
+                ADD $b
+            }
+            ADD_TWO!(a)
+            ADD_TWO!(a, b)
+            ADD_TWO!(a, b, c)",
+        )
+        .unwrap();
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.get_message())
+                .collect::<Vec<_>>(),
+            vec![
+                "macro `ADD_TWO` takes 2 arguments but 1 was supplied",
+                "macro `ADD_TWO` takes 2 arguments but 3 were supplied"
+            ]
+        );
+        assert!(errors[0]
+            .render(&Default::default())
+            .contains("`ADD_TWO` is declared as `ADD_TWO($a, $b)`"));
+    }
+
+    #[test]
+    fn test_overloaded_macros() {
+        let program = parse(
+            "macro ADD_ALL($a) = {
+                ADD $a
+            }
+            macro ADD_ALL($a, $b) = {
+                ADD_ALL!($a)
+                ADD $b
+            }
+            ADD_ALL!(x)
+            ADD_ALL!(x, y)",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            "\tADD\tx\n\tADD\tx\n\tADD\ty\n"
+        );
+
+        let program = parse(
+            "macro ADD_ALL($a) = {
+                ADD $a
+            }
+            macro ADD_ALL($a, $b) = {
+                ADD $a
+                ADD $b
+            }
+            ADD_ALL!()",
+        )
+        .unwrap();
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors[0].get_message(),
+            "macro `ADD_ALL` takes 1 or 2 arguments but 0 were supplied"
+        );
+
+        let program = parse(
+            "macro TWICE($a) = {
+                ADD $a
+            }
+            macro TWICE($b) = {
+                SUB $b
+            }",
+        )
+        .unwrap();
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors[0].get_message(),
+            "macro `TWICE` is already declared with 1 argument"
+        );
+    }
+
+    #[test]
+    fn test_default_arguments() {
+        let program = parse(
+            "macro COPY($src, $dst = acc_tmp) = {
+                LDA $src
+                STO $dst
+            }
+            COPY!(a)
+            COPY!(a, b)",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            "\tLDA\ta\n\tSTO\tacc_tmp\n\tLDA\ta\n\tSTO\tb\n"
+        );
+
+        // a call with one argument could use either declaration
+        let program = parse(
+            "macro COPY($src) = {
+                LDA $src
+            }
+            macro COPY($src, $dst = acc_tmp) = {
+                LDA $src
+                STO $dst
+            }",
+        )
+        .unwrap();
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors[0].get_message(),
+            "macro `COPY` is already declared with 1 argument"
+        );
+    }
+
+    #[test]
+    fn test_variadic_macros() {
+        let program = parse(
+            "macro SUM($first, $rest...) = {
+                LDA $first
+                for $x in $rest {
+                    ADD $x
+                }
+            }
+            macro TOTAL($to, $values...) = {
+                SUM!($values)
+                STO $to
+            }
+            SUM!(a)
+            TOTAL!(t, a, b+1, c)",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            "\tLDA\ta\n\
+             \tLDA\ta\n\tADD\tb+1\n\tADD\tc\n\tSTO\tt\n"
+        );
+
+        let program = parse(
+            "macro SUM($first, $rest...) = {
+                LDA $first
+            }
+            SUM!()",
+        )
+        .unwrap();
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors[0].get_message(),
+            "macro `SUM` takes at least 1 argument but 0 were supplied"
+        );
+        assert!(errors[0]
+            .render(&Default::default())
+            .contains("`SUM` is declared as `SUM($first, $rest...)`"));
+    }
+
+    #[test]
+    fn test_for_each() {
+        let program = parse(
+            "for $x in a, b {
+                loop LDA $x
+                BRZ loop
+            }",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            "loop_1\tLDA\ta\n\tBRZ\tloop_1\nloop_2\tLDA\tb\n\tBRZ\tloop_2\n"
+        );
+    }
+
+    #[test]
+    fn test_local_storage() {
+        let program = parse(
+            "macro SWAP($a, $b) = {
+                local $tmp
+                LDA $a
+                STO $tmp
+                LDA $b
+                STO $a
+                LDA $tmp
+                STO $b
+            }
+            macro COUNT($n) = {
+                local $count = $n
+                LDA $count
+            }
+            SWAP!(x, y)
+            COUNT!(3)
+            HLT",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            "\tLDA\tx\n\tSTO\ttmp_1\n\tLDA\ty\n\tSTO\tx\n\tLDA\ttmp_1\n\tSTO\ty\n\
+             \tLDA\tcount_1\n\
+             \tHLT\n\
+             tmp_1\tDAT\n\
+             count_1\tDAT\t3\n"
+        );
+
+        let program = parse("local $tmp").unwrap();
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors[0].get_message(),
+            "`local $tmp` can only be declared inside a macro, repeat or for block"
+        );
+    }
+
+    #[test]
+    fn test_top_level_global() {
+        let program = parse("IN\nglobal done\nHLT").unwrap();
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+
+        assert_eq!(
+            errors[0].get_message(),
+            "`global` can only be used inside a macro, repeat or for block"
+        );
+    }
+
+    #[test]
+    fn test_recursive_macro() {
+        let program = parse(
+            "macro A() = {
+                B!()
+            }
+            macro B() = {
+                OUT
+                A!()
+            }
+            macro C() = {
+                C!()
+            }
+            A!()
+            C!()",
+        )
+        .unwrap();
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.get_message())
+                .collect::<Vec<_>>(),
+            vec![
+                "recursive macro expansion: A → B → A",
+                "recursive macro expansion: C → C"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expansion_depth() {
+        let program = parse(
+            "macro A() = {
+                B!()
+            }
+            macro B() = {
+                C!()
+            }
+            macro C() = {
+                OUT
+            }
+            A!()",
+        )
+        .unwrap();
+
+        let options = ExpandOptions {
+            max_expansion_depth: 2,
+            ..Default::default()
+        };
+        let errors = replace_macro(&program, &options).unwrap_err();
+        assert_eq!(
+            errors[0].get_message(),
+            "macro expansion exceeded the maximum depth of 2: A → B → C"
+        );
+
+        assert!(replace_macro(&program, &ExpandOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn test_hygienic_labels() {
+        let program = parse(
+            "macro SKIP_ZERO($value) = {
+                LDA $value
+                BRZ skip
+                OUT
+                skip HLT
+            }
+            SKIP_ZERO!(a)
+            SKIP_ZERO!(skip)
+            skip_1 DAT 0",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            // the second call's argument refers to the caller's label, not the macro's
+            "\tLDA\ta\n\tBRZ\tskip_2\n\tOUT\nskip_2\tHLT\n\
+             \tLDA\tskip\n\tBRZ\tskip_3\n\tOUT\nskip_3\tHLT\n\
+             skip_1\tDAT\t0\n"
+        );
+
+        // names of constants are taken too
+        let program = parse(
+            "macro WAIT() = {
+                loop BRZ loop
+            }
+            const loop_1 = 5
+            WAIT!()
+            LDA loop_1",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            "loop_2\tBRZ\tloop_2\n\tLDA\t5\n"
+        );
+    }
+
+    #[test]
+    fn test_opcode_parameters() {
+        let program = parse(
+            "macro APPLY($op, $value) = {
+                LDA total
+                $op $value
+                STO total
+            }
+            APPLY!(ADD, x)
+            APPLY!(sub, y)",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            "\tLDA\ttotal\n\tADD\tx\n\tSTO\ttotal\n\
+             \tLDA\ttotal\n\tSUB\ty\n\tSTO\ttotal\n"
+        );
+
+        let program = parse(
+            "macro APPLY($op, $value) = {
+                $op $value
+            }
+            APPLY!(MUL, x)",
+        )
+        .unwrap();
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(errors[0].get_message(), "`MUL` is not a valid opcode");
+
+        // arguments have to be opcodes in the chosen dialect, and can't be given operands they don't take
+        let program = parse(
+            "macro APPLY($op, $value) = {
+                $op $value
+            }
+            APPLY!(STA, x)
+            APPLY!(OTC, x)",
+        )
+        .unwrap();
+
+        let options = ExpandOptions {
+            dialect: Dialect::durham(),
+            ..Default::default()
+        };
+        let errors = replace_macro(&program, &options).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(Diagnostic::get_message)
+                .collect::<Vec<_>>(),
+            vec![
+                "opcode `STA` is not available in the Durham dialect",
+                "opcode `OTC` is not available in the Durham dialect"
+            ]
+        );
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].get_message(), "`OTC` does not take an operand");
+    }
+
+    #[test]
+    fn test_expression_operands() {
+        let program = parse(
+            "macro AT($table, $index) = {
+                loop LDA $table+$index*2
+                BR loop+1
+            }
+            const N = 3
+            AT!(table, N-1)",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            // arguments which are expressions are bracketed, so they keep their meaning
+            "loop_1\tLDA\ttable+(3-1)*2\n\tBR\tloop_1+1\n"
+        );
+    }
+
+    #[test]
+    fn test_repeat() {
+        let program = parse(
+            "const N = 2
+            macro TIMES($n, $x) = {
+                repeat $n {
+                    ADD $x
+                }
+            }
+            repeat N as $i {
+                loop LDA table+$i
+                BRZ loop
+                TIMES!(N-1, $i)
+            }
+            repeat 0 {
+                OUT
+            }",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(
+            crate::render(&output, Default::default()),
+            "loop_1\tLDA\ttable+0\n\tBRZ\tloop_1\n\tADD\t0\n\
+             loop_2\tLDA\ttable+1\n\tBRZ\tloop_2\n\tADD\t1\n"
+        );
+
+        // constants declared by a macro can be used once it has been called
+        let program = parse(
+            "macro TWICE() = {
+                const K = 2
+                repeat K {
+                    OUT
+                }
+            }
+            TWICE!()",
+        )
+        .unwrap();
+
+        let output = replace_macro(&program, &ExpandOptions::default()).unwrap();
+        assert_eq!(crate::render(&output, Default::default()), "\tOUT\n\tOUT\n");
+
+        let program = parse("repeat M {\n OUT\n}\nrepeat 1000 {\n OUT\n}").unwrap();
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.get_message())
+                .collect::<Vec<_>>(),
+            vec![
+                "undefined constant `M`, repeat counts can only use numbers and constants",
+                "repeat count `1000` evaluates to 1000, expected a value from 0 to 999"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expanded_item_limit() {
+        let program = parse(
+            "repeat 999 {
+                repeat 999 {
+                    repeat 999 {
+                        OUT
+                    }
+                }
+            }",
+        )
+        .unwrap();
+
+        let errors = replace_macro(&program, &ExpandOptions::default()).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|error| error.get_message())
+                .collect::<Vec<_>>(),
+            vec!["expansion produced more than the maximum of 100000 items"]
+        );
+
+        // macros which call another macro more than once can grow just as quickly
+        let program = parse(
+            "macro A() = {\n B!()\n B!()\n}
+            macro B() = {\n OUT\n OUT\n}
+            A!()",
+        )
+        .unwrap();
+
+        let options = ExpandOptions {
+            max_expanded_items: 3,
+            ..Default::default()
+        };
+        let errors = replace_macro(&program, &options).unwrap_err();
+        assert_eq!(
+            errors[0].get_message(),
+            "expansion produced more than the maximum of 3 items"
+        );
+        assert_eq!(
+            replace_macro(&program, &ExpandOptions::default())
+                .unwrap()
+                .len(),
+            4
+        );
+    }
 }
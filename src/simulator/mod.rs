@@ -0,0 +1,291 @@
+use std::fmt::{self, Display, Formatter};
+
+use crate::assembler::{MemoryImage, MAILBOXES};
+
+/// Errors that can stop a program before it halts
+#[derive(PartialEq, Debug, Clone)]
+pub enum RuntimeError {
+    /// The program ran for more than the allowed number of steps
+    StepLimit(usize),
+    /// An IN instruction was reached, but there was no more input
+    MissingInput { address: usize },
+    /// An input value didn't fit in a mailbox
+    InvalidInput { address: usize, value: u16 },
+    /// The mailbox being executed doesn't contain a valid instruction
+    InvalidInstruction { address: usize, code: u16 },
+    /// The program counter moved past the last mailbox
+    OutOfBounds,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::StepLimit(steps) => {
+                write!(f, "program did not halt within {} steps", steps)
+            }
+            RuntimeError::MissingInput { address } => {
+                write!(f, "ran out of input at mailbox {:02}", address)
+            }
+            RuntimeError::InvalidInput { address, value } => write!(
+                f,
+                "input {} at mailbox {:02} is out of range, expected a value from 0 to 999",
+                value, address
+            ),
+            RuntimeError::InvalidInstruction { address, code } => {
+                write!(
+                    f,
+                    "invalid instruction {:03} at mailbox {:02}",
+                    code, address
+                )
+            }
+            RuntimeError::OutOfBounds => write!(f, "program ran past the last mailbox"),
+        }
+    }
+}
+
+/// A value written by an output instruction
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Output {
+    /// The accumulator written as a number, by OUT
+    Number(u16),
+    /// The accumulator written as an ASCII character, by OTC
+    Character(char),
+}
+
+impl Display for Output {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Output::Number(value) => write!(f, "{}", value),
+            Output::Character(character) => write!(f, "{}", character),
+        }
+    }
+}
+
+/// A little man computer, which can run an assembled program
+#[derive(PartialEq, Debug, Clone)]
+pub struct Machine {
+    memory: [u16; MAILBOXES],
+    accumulator: u16,
+    program_counter: usize,
+    negative: bool,
+    halted: bool,
+}
+
+impl Machine {
+    /// Creates a new machine with the given program loaded into its mailboxes
+    pub fn new(image: &MemoryImage) -> Self {
+        Self {
+            memory: image.to_memory(),
+            accumulator: 0,
+            program_counter: 0,
+            negative: false,
+            halted: false,
+        }
+    }
+
+    /// Gets the value in the accumulator
+    pub fn get_accumulator(&self) -> u16 {
+        self.accumulator
+    }
+
+    /// Gets the address of the next instruction to run
+    pub fn get_program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    /// Returns true if the last subtraction went below zero
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// Returns true if the program has halted
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Gets the contents of all mailboxes
+    pub fn get_memory(&self) -> &[u16; MAILBOXES] {
+        &self.memory
+    }
+
+    /// Runs the program until it halts, reading from the given input and passing each output value to the callback.
+    /// Returns the number of steps taken, or an error if the program doesn't halt within the step limit.
+    pub fn run(
+        &mut self,
+        input: impl IntoIterator<Item = u16>,
+        max_steps: usize,
+        mut output: impl FnMut(Output),
+    ) -> Result<usize, RuntimeError> {
+        let mut input = input.into_iter();
+
+        for steps in 0..max_steps {
+            if self.halted {
+                return Ok(steps);
+            }
+
+            if let Some(value) = self.step(&mut input)? {
+                output(value);
+            }
+        }
+
+        if self.halted {
+            Ok(max_steps)
+        } else {
+            Err(RuntimeError::StepLimit(max_steps))
+        }
+    }
+
+    /// Runs a single instruction, returning the value output by the instruction if there is one
+    pub fn step(
+        &mut self,
+        input: &mut impl Iterator<Item = u16>,
+    ) -> Result<Option<Output>, RuntimeError> {
+        let address = self.program_counter;
+        let code = *self.memory.get(address).ok_or(RuntimeError::OutOfBounds)?;
+        let operand = (code % 100) as usize;
+
+        self.program_counter += 1;
+
+        match code / 100 {
+            0 => self.halted = true,
+            1 => {
+                self.accumulator = (self.accumulator + self.memory[operand]) % 1000;
+                self.negative = false;
+            }
+            2 => {
+                // values below zero wrap around, with the negative flag recording that it happened
+                let value = self.memory[operand];
+                self.negative = value > self.accumulator;
+                self.accumulator = (self.accumulator + 1000 - value) % 1000;
+            }
+            3 => self.memory[operand] = self.accumulator,
+            5 => {
+                self.accumulator = self.memory[operand];
+                self.negative = false;
+            }
+            6 => self.program_counter = operand,
+            7 if self.accumulator == 0 && !self.negative => self.program_counter = operand,
+            8 if !self.negative => self.program_counter = operand,
+            7 | 8 => {}
+            9 if code == 901 => {
+                let value = input.next().ok_or(RuntimeError::MissingInput { address })?;
+                if value > 999 {
+                    return Err(RuntimeError::InvalidInput { address, value });
+                }
+
+                self.accumulator = value;
+                self.negative = false;
+            }
+            9 if code == 902 => return Ok(Some(Output::Number(self.accumulator))),
+            9 if code == 922 => {
+                // values which aren't a character are written as the replacement character
+                let character =
+                    char::from_u32(self.accumulator as u32).unwrap_or(char::REPLACEMENT_CHARACTER);
+                return Ok(Some(Output::Character(character)));
+            }
+            _ => return Err(RuntimeError::InvalidInstruction { address, code }),
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{assembler::assemble, parse};
+
+    /// Assembles and runs the program with the given input, returning everything it outputs
+    fn run(program: &str, input: Vec<u16>) -> Result<Vec<Output>, RuntimeError> {
+        let image = assemble(&parse(program).unwrap()).unwrap();
+        let mut output = Vec::new();
+
+        Machine::new(&image).run(input, 1000, |value| output.push(value))?;
+        Ok(output)
+    }
+
+    #[test]
+    fn test_division() {
+        let program = "IN
+            STO a
+            IN
+            STO b
+        start LDA count
+            ADD one
+            STO count
+            LDA a
+            SUB b
+            STO a
+            BRP start
+        done LDA count
+            SUB one
+            OUT
+            HLT
+        a DAT 000
+        b DAT 000
+        count DAT 000
+        one DAT 001";
+
+        assert_eq!(run(program, vec![20, 5]), Ok(vec![Output::Number(4)]));
+        assert_eq!(run(program, vec![99, 10]), Ok(vec![Output::Number(9)]));
+    }
+
+    #[test]
+    fn test_negative_flag() {
+        let program = "LDA five
+            SUB ten
+            BRP positive
+            OUT
+            BRZ zero
+            HLT
+        positive HLT
+        zero HLT
+        five DAT 5
+        ten DAT 10";
+
+        // 5 - 10 wraps around to 995, and the negative flag stops both branches
+        assert_eq!(run(program, vec![]), Ok(vec![Output::Number(995)]));
+    }
+
+    #[test]
+    fn test_character_output() {
+        let program = "INP
+            OTC
+            STA a
+            LDA a
+            OUT
+            HLT
+        a DAT";
+
+        assert_eq!(
+            run(program, vec![72]),
+            Ok(vec![Output::Character('H'), Output::Number(72)])
+        );
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        assert_eq!(
+            run("IN\nIN\nHLT", vec![1]),
+            Err(RuntimeError::MissingInput { address: 1 })
+        );
+        assert_eq!(
+            run("IN\nHLT", vec![1000]),
+            Err(RuntimeError::InvalidInput {
+                address: 0,
+                value: 1000
+            })
+        );
+        assert_eq!(
+            run("loop BR loop", vec![]),
+            Err(RuntimeError::StepLimit(1000))
+        );
+        assert_eq!(
+            run("LDA x\nx DAT 400", vec![]),
+            Err(RuntimeError::InvalidInstruction {
+                address: 1,
+                code: 400
+            })
+        );
+    }
+}